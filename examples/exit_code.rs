@@ -1,5 +1,4 @@
 use rexpect::error::Error;
-use rexpect::process::WaitStatus;
 use rexpect::spawn;
 
 /// The following code emits:
@@ -9,19 +8,18 @@ use rexpect::spawn;
 fn main() -> Result<(), Error> {
     let p = spawn("cat /etc/passwd", Some(2000))?;
     match p.process().wait() {
-        Ok(WaitStatus::Exited(_, 0)) => println!("cat exited with code 0, all good!"),
+        Ok(report) if report.success() => println!("cat exited with code 0, all good!"),
         _ => println!("cat exited with code >0, or it was killed"),
     }
 
     let mut p = spawn("cat /this/does/not/exist", Some(2000))?;
-    match p.process().wait() {
-        Ok(WaitStatus::Exited(_, 0)) => println!("cat succeeded"),
-        Ok(WaitStatus::Exited(_, c)) => {
+    match p.process().wait().map(|report| report.exit_code()) {
+        Ok(Some(0)) => println!("cat succeeded"),
+        Ok(Some(c)) => {
             println!("Cat failed with exit code {c}");
             println!("Output (stdout and stderr): {}", p.exp_eof()?);
         }
-        // for other possible return types of wait()
-        // see here: https://tailhook.github.io/rotor/nix/sys/wait/enum.WaitStatus.html
+        // `ExitReport::signal()` tells which signal terminated the process
         _ => println!("cat was probably killed"),
     }
 
@@ -0,0 +1,169 @@
+//! Recording of sessions in asciicast v2 format, see [`Options::record_asciicast`](crate::reader::Options::record_asciicast)
+//!
+//! The format is described at <https://docs.asciinema.org/manual/asciicast/v2/>
+
+use crate::log::{Direction, Record};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::time;
+
+/// Environment variables of the process which are recorded in the header
+pub(crate) const RECORDED_ENV: [&str; 2] = ["SHELL", "TERM"];
+
+/// Window size recorded if the session doesn't run in a pty (columns, rows)
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+pub(crate) struct Asciicast {
+    writer: Box<dyn Write + Send>,
+    start: Option<time::Instant>,
+    /// Trailing bytes of incomplete UTF-8 sequences, per direction
+    pending_read: Vec<u8>,
+    pending_send: Vec<u8>,
+}
+
+impl Asciicast {
+    pub(crate) fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer,
+            start: None,
+            pending_read: Vec::new(),
+            pending_send: Vec::new(),
+        }
+    }
+}
+
+impl Record for Asciicast {
+    fn start(
+        &mut self,
+        window_size: Option<(u16, u16)>,
+        env: Option<&[(String, String)]>,
+    ) -> io::Result<()> {
+        if self.start.is_some() {
+            return Ok(());
+        }
+        let (width, height) = match window_size {
+            Some((rows, cols)) if rows > 0 && cols > 0 => (cols, rows),
+            _ => DEFAULT_SIZE,
+        };
+        let timestamp = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        // without the environment of the process, assume it inherited ours
+        let env = env.map(<[_]>::to_vec).unwrap_or_else(|| {
+            RECORDED_ENV
+                .iter()
+                .filter_map(|&name| Some((name.to_owned(), std::env::var(name).ok()?)))
+                .collect()
+        });
+        let env = env
+            .iter()
+            .map(|(name, value)| format!("{}: {}", json_string(name), json_string(value)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(
+            self.writer,
+            "{{\"version\": 2, \"width\": {width}, \"height\": {height}, \
+             \"timestamp\": {timestamp}, \"env\": {{{env}}}}}"
+        )?;
+        self.start = Some(time::Instant::now());
+        self.writer.flush()
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        self.start(None, None)?;
+        let elapsed = self.start.map(|start| start.elapsed()).unwrap_or_default();
+        let (code, pending) = match direction {
+            Direction::Read => ("o", &mut self.pending_read),
+            Direction::Send => ("i", &mut self.pending_send),
+        };
+        pending.extend_from_slice(data);
+        let text = take_utf8(pending);
+        if text.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            self.writer,
+            "[{:.6}, \"{code}\", {}]",
+            elapsed.as_secs_f64(),
+            json_string(&text)
+        )?;
+        self.writer.flush()
+    }
+}
+
+/// Decode as much of `bytes` as possible, an incomplete sequence at the end is kept
+/// for the next chunk and invalid sequences are replaced by U+FFFD
+fn take_utf8(bytes: &mut Vec<u8>) -> String {
+    let mut text = String::new();
+    let mut rest = &bytes[..];
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                text.push_str(valid);
+                rest = &[];
+                break;
+            }
+            Err(err) => {
+                let (valid, after) = rest.split_at(err.valid_up_to());
+                // SAFETY: checked by from_utf8 above
+                text.push_str(unsafe { std::str::from_utf8_unchecked(valid) });
+                match err.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        rest = &after[len..];
+                    }
+                    None => {
+                        rest = after;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    let rest = rest.len();
+    bytes.drain(..bytes.len() - rest);
+    text
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_utf8() {
+        let mut bytes = b"a\xc3".to_vec();
+        assert_eq!("a", take_utf8(&mut bytes));
+        bytes.extend_from_slice(b"\xa4\xffb");
+        assert_eq!("ä\u{fffd}b", take_utf8(&mut bytes));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(
+            r#""a\"b\\c\r\n\u001b[0m""#,
+            json_string("a\"b\\c\r\n\x1b[0m")
+        );
+    }
+}
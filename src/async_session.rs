@@ -0,0 +1,568 @@
+//! Async sessions for the tokio runtime, enabled with the `tokio` feature
+//!
+//! Unlike the blocking sessions no background thread is used:
+//! the output is read on demand while waiting for a needle.
+
+use crate::error::Error;
+use crate::keys;
+use crate::log::Log;
+use crate::process::{ExitReport, PtyProcess};
+use crate::reader::{EscapeCodeFilter, Options, ReadUntil, Regex, take_match};
+use crate::session::{EXIT_OUTPUT_GRACE, recorded_env};
+use std::fs::File;
+use std::future::{Future, poll_fn};
+use std::io::{self, Read, Write};
+use std::mem::ManuallyDrop;
+use std::pin::{Pin, pin};
+use std::process::Command;
+use std::task::{Context, Poll, ready};
+use std::time;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::signal::unix::{Signal, SignalKind, signal};
+
+/// Async version of [`StreamSession`](crate::session::StreamSession)
+///
+/// Of the [`Options`], only the timeout, the stripping of escape codes, the logs and the
+/// recorders apply. The ones which concern the writer or reader of the blocking sessions
+/// (e.g. [`Options::poll_reader`], [`Options::writer_thread`], [`Options::write_timeout_ms`]
+/// and [`Options::typing_delay_ms`]) are ignored, as is [`Options::term`] except for
+/// setting `TERM` in [`spawn_async`].
+pub struct AsyncStreamSession<R, W> {
+    reader: R,
+    writer: W,
+    buffer: String,
+    eof: bool,
+    timeout: Option<time::Duration>,
+    filter: EscapeCodeFilter,
+    read_log: Log,
+    send_log: Log,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncStreamSession<R, W> {
+    pub fn new(reader: R, writer: W, mut options: Options) -> Self {
+        options.start_recording(None);
+        Self {
+            reader,
+            writer,
+            buffer: String::with_capacity(1024),
+            eof: false,
+            timeout: options.timeout_ms.map(time::Duration::from_millis),
+            filter: EscapeCodeFilter::new(options.strip_ansi_escape_codes),
+            read_log: options.take_read_log(),
+            send_log: options.take_send_log(),
+        }
+    }
+
+    /// Sends string and a newline to process
+    ///
+    /// Returns number of written bytes
+    pub async fn send_line(&mut self, line: &str) -> Result<usize, Error> {
+        let mut data = String::with_capacity(line.len() + 1);
+        data.push_str(line);
+        data.push('\n');
+        self.send(&data).await
+    }
+
+    /// Send string to process, it is flushed right away
+    ///
+    /// Returns number of written bytes
+    pub async fn send(&mut self, s: &str) -> Result<usize, Error> {
+        self.send_bytes(s.as_bytes()).await?;
+        Ok(s.len())
+    }
+
+    /// Send a control code to the running process, see
+    /// [`StreamSession::send_control`](crate::session::StreamSession::send_control)
+    pub async fn send_control(&mut self, c: char) -> Result<(), Error> {
+        let code = keys::control_code(c).ok_or(Error::SendControlError(c))?;
+        self.send_bytes(&[code]).await
+    }
+
+    async fn send_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(data).await?;
+        self.writer.flush().await?;
+        self.send_log.log(data);
+        Ok(())
+    }
+
+    /// Read until needle is found, see [`NBReader::read_until`](crate::reader::NBReader::read_until)
+    pub async fn read_until(&mut self, needle: &ReadUntil) -> Result<(String, String), Error> {
+        let start = time::Instant::now();
+        loop {
+            if let Some(found) = take_match(&mut self.buffer, needle, self.eof)? {
+                return Ok(found);
+            }
+
+            let read = match self.timeout {
+                Some(timeout) => {
+                    let remaining = timeout.saturating_sub(start.elapsed());
+                    match tokio::time::timeout(remaining, self.read_into_buffer()).await {
+                        Ok(read) => read,
+                        Err(_) => {
+                            return Err(Error::Timeout {
+                                expected: needle.to_string(),
+                                got: self.buffer.clone(),
+                                timeout,
+                            });
+                        }
+                    }
+                }
+                None => self.read_into_buffer().await,
+            };
+            read?;
+        }
+    }
+
+    /// Read what is available (at least one byte or EOF) into the buffer
+    async fn read_into_buffer(&mut self) -> Result<(), Error> {
+        let mut chunk = [0; 1024];
+        match self.reader.read(&mut chunk).await {
+            Ok(0) => self.eof = true,
+            Ok(n) => {
+                self.read_log.log(&chunk[..n]);
+                for &byte in &chunk[..n] {
+                    if self.filter.keep(byte) {
+                        self.buffer.push(byte as char);
+                    }
+                }
+            }
+            // a pty returns EIO once all processes using it exited
+            Err(err) if err.raw_os_error() == Some(5) => self.eof = true,
+            Err(err) => return Err(err.into()),
+        }
+        Ok(())
+    }
+
+    /// Read what is available into the buffer or wait until `signal` arrived, whichever is first
+    ///
+    /// At EOF this only waits for the signal.
+    async fn read_or_wait_for(&mut self, signal: &mut Signal) -> Result<(), Error> {
+        if self.eof {
+            signal.recv().await;
+            return Ok(());
+        }
+        let mut read = pin!(self.read_into_buffer());
+        let mut received = pin!(signal.recv());
+        poll_fn(|cx| {
+            if let Poll::Ready(result) = read.as_mut().poll(cx) {
+                return Poll::Ready(result);
+            }
+            received.as_mut().poll(cx).map(|_| Ok(()))
+        })
+        .await
+    }
+
+    /// Whether the end of the stream was reached, without waiting for more output
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// Wait until EOF, see [`StreamReader::exp_eof`](crate::session::StreamReader::exp_eof)
+    pub async fn exp_eof(&mut self) -> Result<String, Error> {
+        self.read_until(&ReadUntil::EOF).await.map(|(_, s)| s)
+    }
+
+    /// See [`StreamReader::exp_regex`](crate::session::StreamReader::exp_regex)
+    pub async fn exp_regex(&mut self, regex: &str) -> Result<(String, String), Error> {
+        self.read_until(&ReadUntil::Regex(Regex::new(regex)?)).await
+    }
+
+    /// See [`StreamReader::exp_string`](crate::session::StreamReader::exp_string)
+    pub async fn exp_string(&mut self, needle: &str) -> Result<String, Error> {
+        self.read_until(&ReadUntil::String(needle.to_owned()))
+            .await
+            .map(|(s, _)| s)
+    }
+
+    /// See [`StreamReader::exp_char`](crate::session::StreamReader::exp_char)
+    pub async fn exp_char(&mut self, needle: char) -> Result<String, Error> {
+        self.read_until(&ReadUntil::String(needle.to_string()))
+            .await
+            .map(|(s, _)| s)
+    }
+
+    /// See [`StreamReader::exp_any`](crate::session::StreamReader::exp_any)
+    pub async fn exp_any(&mut self, needles: Vec<ReadUntil>) -> Result<(String, String), Error> {
+        self.read_until(&ReadUntil::Any(needles)).await
+    }
+
+    /// See [`StreamReader::read_line`](crate::session::StreamReader::read_line)
+    pub async fn read_line(&mut self) -> Result<String, Error> {
+        let (mut line, _) = self
+            .read_until(&ReadUntil::String('\n'.to_string()))
+            .await?;
+        if line.ends_with('\r') {
+            line.pop();
+        }
+        Ok(line)
+    }
+}
+
+/// Async version of [`PtySession`](crate::session::PtySession)
+///
+/// # Example
+///
+/// ```
+/// use rexpect::async_session::spawn_async;
+/// use rexpect::reader::Options;
+/// use std::process::Command;
+/// # use rexpect::error::Error;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), Error> {
+/// let mut p = spawn_async(Command::new("cat"), Options::new().timeout_ms(Some(1000)))?;
+/// p.send_line("hello, polly!").await?;
+/// assert_eq!("hello, polly!", p.read_line().await?);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The pty is switched to non-blocking mode, which also applies to the handles returned
+/// by [`PtyProcess::get_file_handle`] as they share the open file description. Reading or
+/// writing them may fail with [`io::ErrorKind::WouldBlock`] instead of waiting.
+///
+/// When the session is dropped while the child is still running, the child is killed
+/// on a thread for blocking tasks, see [`tokio::task::spawn_blocking`].
+pub struct AsyncPtySession {
+    /// Taken out when dropped, see [`Drop`]
+    process: ManuallyDrop<PtyProcess>,
+    stream: AsyncStreamSession<AsyncPty, AsyncPty>,
+}
+
+impl std::ops::Deref for AsyncPtySession {
+    type Target = AsyncStreamSession<AsyncPty, AsyncPty>;
+    fn deref(&self) -> &Self::Target {
+        &self.stream
+    }
+}
+
+impl std::ops::DerefMut for AsyncPtySession {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.stream
+    }
+}
+
+impl AsyncPtySession {
+    /// Must be called from within a tokio runtime
+    pub fn new(process: PtyProcess, mut options: Options) -> Result<Self, Error> {
+        options.start_responder(&process)?;
+        options.start_recording(process.window_size().ok());
+        let reader = AsyncPty::new(process.get_file_handle()?)?;
+        let writer = AsyncPty::new(process.get_file_handle()?)?;
+        let stream = AsyncStreamSession::new(reader, writer, options);
+        Ok(Self {
+            process: ManuallyDrop::new(process),
+            stream,
+        })
+    }
+
+    pub fn process(&self) -> &PtyProcess {
+        &self.process
+    }
+
+    pub fn process_mut(&mut self) -> &mut PtyProcess {
+        &mut self.process
+    }
+
+    /// Exit report of the child process if it has terminated (non-blocking).
+    pub fn exit_report(&self) -> Option<ExitReport> {
+        self.process.exit_report()
+    }
+
+    /// Fill in the exit report of EOF errors, the child usually exits right after
+    /// closing the pty so give it a moment to terminate
+    async fn with_exit_report<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        let Err(Error::EOF {
+            expected,
+            got,
+            exit_report: None,
+        }) = result
+        else {
+            return result;
+        };
+        let mut exit_report = self.process.exit_report();
+        for _ in 0..10 {
+            if exit_report.is_some() {
+                break;
+            }
+            tokio::time::sleep(time::Duration::from_millis(10)).await;
+            exit_report = self.process.exit_report();
+        }
+        Err(Error::EOF {
+            expected,
+            got,
+            exit_report,
+        })
+    }
+
+    /// See [`AsyncStreamSession::read_until`], EOF errors contain the exit report of the child
+    pub async fn read_until(&mut self, needle: &ReadUntil) -> Result<(String, String), Error> {
+        let result = self.stream.read_until(needle).await;
+        self.with_exit_report(result).await
+    }
+
+    /// See [`AsyncStreamSession::exp_regex`], EOF errors contain the exit report of the child
+    pub async fn exp_regex(&mut self, regex: &str) -> Result<(String, String), Error> {
+        let result = self.stream.exp_regex(regex).await;
+        self.with_exit_report(result).await
+    }
+
+    /// See [`AsyncStreamSession::exp_string`], EOF errors contain the exit report of the child
+    pub async fn exp_string(&mut self, needle: &str) -> Result<String, Error> {
+        let result = self.stream.exp_string(needle).await;
+        self.with_exit_report(result).await
+    }
+
+    /// See [`AsyncStreamSession::exp_char`], EOF errors contain the exit report of the child
+    pub async fn exp_char(&mut self, needle: char) -> Result<String, Error> {
+        let result = self.stream.exp_char(needle).await;
+        self.with_exit_report(result).await
+    }
+
+    /// See [`AsyncStreamSession::exp_any`], EOF errors contain the exit report of the child
+    pub async fn exp_any(&mut self, needles: Vec<ReadUntil>) -> Result<(String, String), Error> {
+        let result = self.stream.exp_any(needles).await;
+        self.with_exit_report(result).await
+    }
+
+    /// See [`AsyncStreamSession::read_line`], EOF errors contain the exit report of the child
+    pub async fn read_line(&mut self) -> Result<String, Error> {
+        let result = self.stream.read_line().await;
+        self.with_exit_report(result).await
+    }
+
+    /// Wait until the child process has terminated, see
+    /// [`PtySession::exp_exit`](crate::session::PtySession::exp_exit)
+    ///
+    /// Like [`Self::wait`] this is woken up by `SIGCHLD`.
+    pub async fn exp_exit(&mut self) -> Result<(String, ExitReport), Error> {
+        let start = time::Instant::now();
+        let mut sigchld = signal(SignalKind::child())?;
+        loop {
+            if let Some(report) = self.process.exit_report() {
+                // the last output of the child may still be in the pty
+                let stream = &mut self.stream;
+                let rest = async {
+                    while !stream.eof {
+                        stream.read_into_buffer().await?;
+                    }
+                    Ok::<_, Error>(())
+                };
+                let _ = tokio::time::timeout(EXIT_OUTPUT_GRACE, rest).await;
+                return Ok((self.stream.buffer.drain(..).collect(), report));
+            }
+
+            // keep reading, the child may block on a full pty otherwise
+            let timeout = self.stream.timeout;
+            let next = self.stream.read_or_wait_for(&mut sigchld);
+            match timeout {
+                Some(timeout) => {
+                    let remaining = timeout.saturating_sub(start.elapsed());
+                    match tokio::time::timeout(remaining, next).await {
+                        Ok(result) => result?,
+                        Err(_) => {
+                            return Err(Error::Timeout {
+                                expected: "child process exit".to_owned(),
+                                got: self.stream.buffer.clone(),
+                                timeout,
+                            });
+                        }
+                    }
+                }
+                None => next.await?,
+            }
+        }
+    }
+
+    /// Wait until the child process has terminated and reap it
+    ///
+    /// A handler for `SIGCHLD` is installed (if not done already by the runtime)
+    /// to be woken up when the child exits.
+    pub async fn wait(&self) -> Result<ExitReport, Error> {
+        let mut sigchld = signal(SignalKind::child())?;
+        loop {
+            // checked after the handler was installed, so that the signal can't be missed
+            if let Some(report) = self.process.exit_report() {
+                return Ok(report);
+            }
+            sigchld.recv().await;
+        }
+    }
+}
+
+impl Drop for AsyncPtySession {
+    fn drop(&mut self) {
+        // SAFETY: the process isn't used anymore after it was taken out
+        let process = unsafe { ManuallyDrop::take(&mut self.process) };
+        // killing a running child waits for it to exit, which must not block the runtime
+        let running = process.exit_report().is_none();
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) if running => {
+                handle.spawn_blocking(move || drop(process));
+            }
+            _ => drop(process),
+        }
+    }
+}
+
+/// Async reading and writing of the pty master, see [`AsyncPtySession`]
+pub struct AsyncPty {
+    fd: AsyncFd<File>,
+}
+
+impl AsyncPty {
+    /// `file` must not be used for blocking I/O anymore, see [`AsyncPtySession`]
+    fn new(file: File) -> Result<Self, Error> {
+        // the runtime must never block, a pty can't be reopened with flags of its own though
+        let flags = nix::fcntl::fcntl(&file, nix::fcntl::FcntlArg::F_GETFL)?;
+        let flags = nix::fcntl::OFlag::from_bits_truncate(flags) | nix::fcntl::OFlag::O_NONBLOCK;
+        nix::fcntl::fcntl(&file, nix::fcntl::FcntlArg::F_SETFL(flags))?;
+        Ok(Self {
+            fd: AsyncFd::new(file)?,
+        })
+    }
+}
+
+impl AsyncRead for AsyncPty {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|fd| fd.get_ref().read(unfilled)) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for AsyncPty {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+            match guard.try_io(|fd| fd.get_ref().write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Start a process in a pty and interact with it asynchronously, see [`AsyncPtySession`]
+///
+/// Like [`spawn_with_options`](crate::spawn_with_options), this must be called from within
+/// a tokio runtime.
+///
+/// To apply [`SpawnOptions`](crate::process::SpawnOptions), create the session with
+/// [`PtyProcess::with_options`] and [`AsyncPtySession::new`].
+pub fn spawn_async(mut command: Command, mut options: Options) -> Result<AsyncPtySession, Error> {
+    if let Some(term) = &options.term {
+        command.env("TERM", term);
+    }
+    #[cfg(feature = "which")]
+    {
+        let _ = which::which(command.get_program())?;
+    }
+    options.recorded_env = Some(recorded_env(&command));
+    let mut process = PtyProcess::new(command)?;
+    process.set_kill_timeout(options.timeout_ms);
+
+    AsyncPtySession::new(process, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_async_pty_session() -> Result<(), Error> {
+        let mut p = spawn_async(Command::new("cat"), Options::new().timeout_ms(Some(1000)))?;
+        p.send_line("hello").await?;
+        assert_eq!("hello", p.read_line().await?);
+        p.send_control('d').await?;
+        let (_, report) = p.exp_exit().await?;
+        assert!(report.success());
+
+        let mut command = Command::new("sleep");
+        command.arg("5");
+        let mut p = spawn_async(command, Options::new().timeout_ms(Some(100)))?;
+        match p.exp_string("never").await {
+            Err(Error::Timeout { .. }) => {}
+            result => panic!("expected a timeout, got {result:?}"),
+        }
+        match p.exp_exit().await {
+            Err(Error::Timeout { .. }) => {}
+            result => panic!("expected a timeout, got {result:?}"),
+        }
+        // the running child is killed on a blocking thread
+        drop(p);
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo $TERM; exit 4"]);
+        let options = Options::new().timeout_ms(Some(1000)).term("vt100");
+        let mut p = spawn_async(command, options)?;
+        assert_eq!("vt100", p.read_line().await?);
+        match p.exp_string("never").await {
+            Err(Error::EOF {
+                exit_report: Some(report),
+                ..
+            }) => assert_eq!(Some(4), report.exit_code()),
+            result => panic!("expected EOF with an exit report, got {result:?}"),
+        }
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 0.1; exit 3"]);
+        let p = spawn_async(command, Options::new())?;
+        assert_eq!(Some(3), p.wait().await?.exit_code());
+
+        let mut command = Command::new("seq");
+        command.args(["1", "20000"]);
+        let mut p = spawn_async(command, Options::new())?;
+        let (output, _) = p.exp_exit().await?;
+        assert!(output.ends_with("20000\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_stream_session() -> Result<(), Error> {
+        let (session, process) = tokio::io::duplex(64);
+        let (reader, writer) = tokio::io::split(session);
+        let (mut process_reader, mut process_writer) = tokio::io::split(process);
+        let options = Options::new()
+            .timeout_ms(Some(1000))
+            .strip_ansi_escape_codes(true);
+        let mut s = AsyncStreamSession::new(reader, writer, options);
+
+        process_writer.write_all(b"\x1b[1mlogin:\x1b[0m ").await?;
+        s.exp_string("login: ").await?;
+        s.send_line("root").await?;
+        let mut input = [0; 5];
+        process_reader.read_exact(&mut input).await?;
+        assert_eq!(b"root\n", &input);
+
+        process_writer.shutdown().await?;
+        s.exp_eof().await?;
+        Ok(())
+    }
+}
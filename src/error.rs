@@ -1,13 +1,14 @@
+use crate::process::ExitReport;
 use std::time;
 
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
 pub enum Error {
-    #[error("EOF (End of File): Expected {:?} but got EOF after reading {:?} process terminated with {:?}", .expected, .got, .exit_code.as_ref().unwrap_or(&"unknown".to_owned()))]
+    #[error("EOF (End of File): Expected {:?} but got EOF after reading {:?} process terminated with {}", .expected, .got, .exit_report.as_ref().map(ToString::to_string).unwrap_or_else(|| "unknown".to_owned()))]
     EOF {
         expected: String,
         got: String,
-        exit_code: Option<String>,
+        exit_report: Option<ExitReport>,
     },
 
     #[error("PipeError")]
@@ -32,6 +33,18 @@ pub enum Error {
     #[error("Did not understand Ctrl-{}", .0)]
     SendControlError(char),
 
+    #[error("The control character {} is disabled for the terminal", .0)]
+    DisabledControlCharacter(String),
+
+    #[error("Invalid key {:?}", .0)]
+    InvalidKey(String),
+
+    #[error("The mouse position (row {}, column {}) can't be encoded", .row, .column)]
+    InvalidMousePosition { row: u16, column: u16 },
+
+    #[error("The modes of the terminal aren't tracked, see Options::track_terminal_modes")]
+    ModesNotTracked,
+
     #[error("Failed to send via MPSC channel")]
     MpscSendError,
 
@@ -41,6 +54,12 @@ pub enum Error {
     #[error("The provided program arguments cannot be parsed")]
     BadProgramArguments,
 
+    #[error("Line of {} bytes is longer than the {} bytes a terminal in canonical mode accepts", .len, .max)]
+    LineTooLong { len: usize, max: usize },
+
+    #[error("Invalid recording in line {}: {}", .line, .reason)]
+    InvalidRecording { line: usize, reason: String },
+
     #[cfg(feature = "which")]
     #[error(transparent)]
     Which(#[from] which::Error),
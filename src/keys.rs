@@ -0,0 +1,352 @@
+//! Special keys and a notation for sequences of keys, see [`StreamWriter::send_keys`],
+//! and mouse events, see [`PtySession::send_mouse`]
+//!
+//! [`StreamWriter::send_keys`]: crate::session::StreamWriter::send_keys
+//! [`PtySession::send_mouse`]: crate::session::PtySession::send_mouse
+
+use crate::error::Error;
+
+/// A key as it is typed on a terminal
+///
+/// Special keys with modifiers, e.g. Control and Left (which xterm sends as `\x1b[1;5D`),
+/// aren't supported, send their sequence directly instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Key {
+    Char(char),
+    /// Control and a character, e.g. `Ctrl('c')` (upper/smaller case does not matter)
+    Ctrl(char),
+    /// Alt (Meta) and a character, sent as escape followed by the character
+    Alt(char),
+    Enter,
+    Tab,
+    /// Shift-Tab
+    BackTab,
+    Backspace,
+    Delete,
+    Escape,
+    Insert,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    /// Function keys `F(1)` to `F(12)`
+    F(u8),
+}
+
+/// Terminals which differ in the encoding of some keys
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Family {
+    Xterm,
+    Linux,
+    Rxvt,
+    Screen,
+}
+
+impl Family {
+    /// Terminal types which aren't known are treated like xterm
+    fn new(term: &str) -> Self {
+        if term.starts_with("linux") {
+            Self::Linux
+        } else if term.starts_with("rxvt") {
+            Self::Rxvt
+        } else if term.starts_with("screen") || term.starts_with("tmux") {
+            Self::Screen
+        } else {
+            Self::Xterm
+        }
+    }
+}
+
+impl Key {
+    /// The bytes a terminal of type `term` (the value of `TERM`) sends for the key
+    ///
+    /// The cursor keys are encoded as in normal (not application) cursor mode.
+    pub fn encode(&self, term: &str) -> Result<Vec<u8>, Error> {
+        let family = Family::new(term);
+        let sequence: &str = match *self {
+            Key::Char(c) => return Ok(c.to_string().into_bytes()),
+            Key::Ctrl(c) => {
+                let code = control_code(c).ok_or(Error::SendControlError(c))?;
+                return Ok(vec![code]);
+            }
+            Key::Alt(c) => return Ok(format!("\x1b{c}").into_bytes()),
+            Key::Enter => "\r",
+            Key::Tab => "\t",
+            Key::BackTab => "\x1b[Z",
+            Key::Backspace => "\x7f",
+            Key::Delete => "\x1b[3~",
+            Key::Escape => "\x1b",
+            Key::Insert => "\x1b[2~",
+            Key::Up => "\x1b[A",
+            Key::Down => "\x1b[B",
+            Key::Right => "\x1b[C",
+            Key::Left => "\x1b[D",
+            Key::Home => match family {
+                Family::Xterm => "\x1b[H",
+                Family::Linux | Family::Screen => "\x1b[1~",
+                Family::Rxvt => "\x1b[7~",
+            },
+            Key::End => match family {
+                Family::Xterm => "\x1b[F",
+                Family::Linux | Family::Screen => "\x1b[4~",
+                Family::Rxvt => "\x1b[8~",
+            },
+            Key::PageUp => "\x1b[5~",
+            Key::PageDown => "\x1b[6~",
+            Key::F(n) => match (family, n) {
+                (Family::Linux, 1..=4) => {
+                    ["\x1b[[A", "\x1b[[B", "\x1b[[C", "\x1b[[D"][usize::from(n - 1)]
+                }
+                (Family::Linux, 5) => "\x1b[[E",
+                (Family::Rxvt, 1..=4) => {
+                    ["\x1b[11~", "\x1b[12~", "\x1b[13~", "\x1b[14~"][usize::from(n - 1)]
+                }
+                (_, 1..=4) => ["\x1bOP", "\x1bOQ", "\x1bOR", "\x1bOS"][usize::from(n - 1)],
+                (_, 5..=12) => [
+                    "\x1b[15~", "\x1b[17~", "\x1b[18~", "\x1b[19~", "\x1b[20~", "\x1b[21~",
+                    "\x1b[23~", "\x1b[24~",
+                ][usize::from(n - 5)],
+                _ => return Err(Error::InvalidKey(format!("F{n}"))),
+            },
+        };
+        Ok(sequence.as_bytes().to_vec())
+    }
+}
+
+/// A mouse button, see [`Mouse`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+}
+
+/// A mouse event as a terminal reports it, see [`PtySession::send_mouse`]
+///
+/// [`PtySession::send_mouse`]: crate::session::PtySession::send_mouse
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Mouse {
+    Press(MouseButton),
+    Release(MouseButton),
+    /// Motion while the button is pressed
+    Drag(MouseButton),
+    ScrollUp,
+    ScrollDown,
+}
+
+/// Largest row or column of the legacy mouse encoding, which sends it as a byte offset by 32
+const MAX_LEGACY_MOUSE_POSITION: u16 = 255 - 32;
+
+impl Mouse {
+    /// The bytes an xterm sends for the event at `row` and `column` (counted from 1),
+    /// in SGR encoding (mode 1006) if `sgr` is set, otherwise in the legacy encoding
+    pub(crate) fn encode(&self, row: u16, column: u16, sgr: bool) -> Result<Vec<u8>, Error> {
+        let out_of_range = if sgr {
+            row == 0 || column == 0
+        } else {
+            !(1..=MAX_LEGACY_MOUSE_POSITION).contains(&row)
+                || !(1..=MAX_LEGACY_MOUSE_POSITION).contains(&column)
+        };
+        if out_of_range {
+            return Err(Error::InvalidMousePosition { row, column });
+        }
+        let button = |button: MouseButton| match button {
+            MouseButton::Left => 0,
+            MouseButton::Middle => 1,
+            MouseButton::Right => 2,
+        };
+        let code: u16 = match *self {
+            Mouse::Press(b) => button(b),
+            // the legacy encoding doesn't tell which button was released
+            Mouse::Release(_) if !sgr => 3,
+            Mouse::Release(b) => button(b),
+            Mouse::Drag(b) => button(b) + 32,
+            Mouse::ScrollUp => 64,
+            Mouse::ScrollDown => 65,
+        };
+        if sgr {
+            let action = if matches!(self, Mouse::Release(_)) {
+                'm'
+            } else {
+                'M'
+            };
+            Ok(format!("\x1b[<{code};{column};{row}{action}").into_bytes())
+        } else {
+            // every value fits into a byte, see above
+            let byte = |value: u16| (value + 32) as u8;
+            Ok(vec![0x1b, b'[', b'M', byte(code), byte(column), byte(row)])
+        }
+    }
+}
+
+/// The code sent for Control and `c`
+pub(crate) fn control_code(c: char) -> Option<u8> {
+    let code = match c {
+        'a'..='z' => c as u8 + 1 - b'a',
+        'A'..='Z' => c as u8 + 1 - b'A',
+        '@' | ' ' => 0,
+        '[' => 27,
+        '\\' => 28,
+        ']' => 29,
+        '^' => 30,
+        '_' => 31,
+        '?' => 127,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Parse a sequence of keys in a notation like the one of vim's mappings
+///
+/// Characters stand for themselves, special keys are written in angle brackets
+/// (upper/smaller case does not matter):
+///
+/// - `<C-x>`: Control and `x`, `<M-x>` or `<A-x>`: Alt and `x`
+/// - `<Enter>` (`<CR>`, `<Return>`), `<Tab>`, `<S-Tab>`, `<BS>` (`<Backspace>`),
+///   `<Del>` (`<Delete>`), `<Esc>` (`<Escape>`), `<Ins>` (`<Insert>`), `<Space>`
+/// - `<Up>`, `<Down>`, `<Left>`, `<Right>`, `<Home>`, `<End>`,
+///   `<PageUp>` (`<PgUp>`), `<PageDown>` (`<PgDn>`)
+/// - `<F1>` to `<F12>`
+/// - `<lt>`: a literal `<`
+///
+/// Modifiers only apply to characters, special keys with modifiers like `<C-Left>`
+/// aren't supported, see [`Key`].
+///
+/// # Example
+///
+/// ```
+/// use rexpect::keys::{Key, parse};
+///
+/// assert_eq!(
+///     vec![Key::Ctrl('a'), Key::Left, Key::Char('x'), Key::Enter],
+///     parse("<C-a><Left>x<Enter>").unwrap()
+/// );
+/// ```
+pub fn parse(notation: &str) -> Result<Vec<Key>, Error> {
+    let mut keys = Vec::new();
+    let mut rest = notation;
+    while let Some(c) = rest.chars().next() {
+        if c != '<' {
+            keys.push(Key::Char(c));
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let end = rest
+            .find('>')
+            .ok_or_else(|| Error::InvalidKey(rest.to_owned()))?;
+        keys.push(
+            parse_key(&rest[1..end]).ok_or_else(|| Error::InvalidKey(rest[..=end].to_owned()))?,
+        );
+        rest = &rest[end + 1..];
+    }
+    Ok(keys)
+}
+
+/// Parse the name of a key between the angle brackets
+fn parse_key(name: &str) -> Option<Key> {
+    let mut chars = name.chars();
+    if let (Some(modifier), Some('-'), Some(c), None) =
+        (chars.next(), chars.next(), chars.next(), chars.next())
+    {
+        return match modifier.to_ascii_uppercase() {
+            'C' => control_code(c).map(|_| Key::Ctrl(c)),
+            'M' | 'A' => Some(Key::Alt(c)),
+            _ => None,
+        };
+    }
+    let key = match name.to_ascii_lowercase().as_str() {
+        "enter" | "cr" | "return" => Key::Enter,
+        "tab" => Key::Tab,
+        "s-tab" => Key::BackTab,
+        "bs" | "backspace" => Key::Backspace,
+        "del" | "delete" => Key::Delete,
+        "esc" | "escape" => Key::Escape,
+        "ins" | "insert" => Key::Insert,
+        "space" => Key::Char(' '),
+        "lt" => Key::Char('<'),
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" | "pgup" => Key::PageUp,
+        "pagedown" | "pgdn" => Key::PageDown,
+        name => {
+            let n = name.strip_prefix('f')?.parse().ok()?;
+            if !(1..=12).contains(&n) {
+                return None;
+            }
+            Key::F(n)
+        }
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            vec![
+                Key::Char('a'),
+                Key::Ctrl('W'),
+                Key::Alt('b'),
+                Key::BackTab,
+                Key::Char('<'),
+                Key::F(12),
+                Key::PageDown,
+                Key::Char('ä'),
+            ],
+            parse("a<C-W><M-b><S-Tab><lt><f12><PgDn>ä").unwrap()
+        );
+        assert!(parse("<C-a").is_err());
+        assert!(parse("<Foo>").is_err());
+        assert!(parse("<F13>").is_err());
+        assert!(parse("<C-1>").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let encode = |key: Key, term| key.encode(term).unwrap();
+        assert_eq!(b"\x01", &encode(Key::Ctrl('a'), "xterm")[..]);
+        assert_eq!(b"\x1bb", &encode(Key::Alt('b'), "xterm")[..]);
+        assert_eq!(b"\x1b[H", &encode(Key::Home, "xterm-256color")[..]);
+        assert_eq!(b"\x1b[1~", &encode(Key::Home, "screen-256color")[..]);
+        assert_eq!(b"\x1b[7~", &encode(Key::Home, "rxvt-unicode")[..]);
+        assert_eq!(b"\x1bOP", &encode(Key::F(1), "")[..]);
+        assert_eq!(b"\x1b[[A", &encode(Key::F(1), "linux")[..]);
+        assert_eq!(b"\x1b[24~", &encode(Key::F(12), "linux")[..]);
+        assert!(Key::F(13).encode("xterm").is_err());
+    }
+
+    #[test]
+    fn test_encode_mouse() {
+        let encode = |mouse: Mouse, row, column, sgr| mouse.encode(row, column, sgr).unwrap();
+        let left = MouseButton::Left;
+        assert_eq!(b"\x1b[M !\"", &encode(Mouse::Press(left), 2, 1, false)[..]);
+        assert_eq!(
+            b"\x1b[M#!\"",
+            &encode(Mouse::Release(left), 2, 1, false)[..]
+        );
+        assert_eq!(
+            b"\x1b[<34;300;2M",
+            &encode(Mouse::Drag(MouseButton::Right), 2, 300, true)[..]
+        );
+        assert_eq!(
+            b"\x1b[<0;1;2m",
+            &encode(Mouse::Release(left), 2, 1, true)[..]
+        );
+        assert_eq!(b"\x1b[<65;5;5M", &encode(Mouse::ScrollDown, 5, 5, true)[..]);
+        assert!(Mouse::ScrollUp.encode(1, 224, false).is_err());
+        assert!(Mouse::ScrollUp.encode(0, 1, true).is_err());
+    }
+}
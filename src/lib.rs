@@ -68,13 +68,27 @@
 #![warn(clippy::print_stderr)]
 #![warn(clippy::print_stdout)]
 
+mod asciicast;
+#[cfg(feature = "tokio")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+pub mod async_session;
 pub mod error;
+pub mod keys;
+mod log;
+pub mod mock;
 pub mod process;
 pub mod reader;
+pub mod replay;
 pub mod session;
+pub mod terminal;
+mod writer;
 
 pub use reader::ReadUntil;
-pub use session::{spawn, spawn_bash, spawn_python, spawn_stream, spawn_with_options};
+pub use session::{
+    spawn, spawn_bash, spawn_bash_with_options, spawn_fd, spawn_pipe,
+    spawn_pipe_with_spawn_options, spawn_python, spawn_stream, spawn_with_options,
+    spawn_with_spawn_options,
+};
 
 // include the README.md here to test its doc
 #[doc = include_str!("../README.md")]
@@ -0,0 +1,182 @@
+//! Logging of the data sent to and read from a process, see [`Options::log`](crate::reader::Options::log)
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    /// Output of the process
+    Read,
+    /// Input sent to the process
+    Send,
+}
+
+/// Records the data of both directions
+pub(crate) trait Record: Send {
+    /// Called when the session starts, with the window size (rows, columns) of the pty
+    /// and the environment variables of the process which are recorded, if known
+    ///
+    /// This may be called more than once, only the first call counts.
+    fn start(
+        &mut self,
+        _window_size: Option<(u16, u16)>,
+        _env: Option<&[(String, String)]>,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()>;
+}
+
+/// Interleaves the data of both directions, every line is prefixed by `< ` (read)
+/// or `> ` (sent) and a change of direction starts a new line
+pub(crate) struct Transcript {
+    writer: Box<dyn Write + Send>,
+    direction: Option<Direction>,
+    line_start: bool,
+}
+
+impl Transcript {
+    pub(crate) fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer,
+            direction: None,
+            line_start: true,
+        }
+    }
+}
+
+impl Record for Transcript {
+    fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        if self.direction != Some(direction) {
+            if !self.line_start {
+                self.writer.write_all(b"\n")?;
+                self.line_start = true;
+            }
+            self.direction = Some(direction);
+        }
+        let marker: &[u8] = match direction {
+            Direction::Read => b"< ",
+            Direction::Send => b"> ",
+        };
+        for line in data.split_inclusive(|b| *b == b'\n') {
+            if self.line_start {
+                self.writer.write_all(marker)?;
+            }
+            self.writer.write_all(line)?;
+            self.line_start = line.ends_with(b"\n");
+        }
+        // flush right away, the transcript is most interesting when something goes wrong
+        self.writer.flush()
+    }
+}
+
+/// Logs the data of one direction
+pub(crate) struct Log {
+    direction: Direction,
+    sink: Option<Box<dyn Write + Send>>,
+    recorders: Vec<Arc<Mutex<dyn Record>>>,
+}
+
+impl Log {
+    pub(crate) fn new(
+        direction: Direction,
+        sink: Option<Box<dyn Write + Send>>,
+        recorders: Vec<Arc<Mutex<dyn Record>>>,
+    ) -> Self {
+        Self {
+            direction,
+            sink,
+            recorders,
+        }
+    }
+
+    /// Errors are ignored, logging must not interfere with the session
+    pub(crate) fn log(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        if let Some(sink) = self.sink.as_mut() {
+            let _ = sink.write_all(data).and_then(|()| sink.flush());
+        }
+        for recorder in &self.recorders {
+            if let Ok(mut recorder) = recorder.lock() {
+                let _ = recorder.record(self.direction, data);
+            }
+        }
+    }
+}
+
+/// Logs everything which is written to the inner writer
+pub(crate) struct LogWriter<W: Write> {
+    inner: W,
+    log: Log,
+    /// The last byte which was written
+    last: Option<u8>,
+}
+
+impl<W: Write> LogWriter<W> {
+    pub(crate) fn new(inner: W, log: Log) -> Self {
+        Self {
+            inner,
+            log,
+            last: None,
+        }
+    }
+
+    /// The last byte which was written, e.g. to know whether a line was started
+    pub(crate) fn last_byte(&self) -> Option<u8> {
+        self.last
+    }
+}
+
+impl<W: Write> Write for LogWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.log.log(&buf[..n]);
+        if n > 0 {
+            self.last = Some(buf[n - 1]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transcript() {
+        let output = Shared::default();
+        let transcript: Arc<Mutex<dyn Record>> =
+            Arc::new(Mutex::new(Transcript::new(Box::new(output.clone()))));
+        let mut send = Log::new(Direction::Send, None, vec![transcript.clone()]);
+        let mut read = Log::new(Direction::Read, None, vec![transcript]);
+
+        send.log(b"echo hi\n");
+        read.log(b"echo");
+        read.log(b" hi\r\nhi\r\n$ ");
+        send.log(b"exit\n");
+        assert_eq!(
+            "> echo hi\n< echo hi\r\n< hi\r\n< $ \n> exit\n",
+            String::from_utf8_lossy(&output.0.lock().unwrap())
+        );
+    }
+}
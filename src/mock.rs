@@ -0,0 +1,333 @@
+//! In-memory pipes and a scripted fake process, to test code using rexpect without spawning anything
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::{thread, time};
+
+/// Create an in-memory pipe: what is written to the [`PipeWriter`] can be read from the
+/// [`PipeReader`]
+///
+/// Reading blocks until data is available and returns EOF once the writer was dropped.
+/// Writing fails with [`io::ErrorKind::BrokenPipe`] once the reader was dropped.
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(PipeState {
+            buffer: VecDeque::new(),
+            reader_closed: false,
+            writer_closed: false,
+        }),
+        changed: Condvar::new(),
+    });
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+/// Create the two ends of an in-memory duplex pipe, what is written to one end
+/// can be read from the other one
+///
+/// # Example
+///
+/// ```
+/// use rexpect::mock::duplex;
+/// use rexpect::spawn_stream;
+/// use std::io::{Read, Write};
+/// # use rexpect::error::Error;
+///
+/// # fn main() -> Result<(), Error> {
+/// let (session, mut process) = duplex();
+/// let (reader, writer) = session.split();
+/// let mut s = spawn_stream(reader, writer, Some(1000));
+/// process.write_all(b"$ ")?;
+/// s.exp_string("$ ")?;
+/// s.send_line("exit")?;
+///
+/// let mut input = [0; 5];
+/// process.read_exact(&mut input)?;
+/// assert_eq!(b"exit\n", &input);
+/// # Ok(())
+/// # }
+/// ```
+pub fn duplex() -> (Duplex, Duplex) {
+    let (a_reader, b_writer) = pipe();
+    let (b_reader, a_writer) = pipe();
+    (
+        Duplex {
+            reader: a_reader,
+            writer: a_writer,
+        },
+        Duplex {
+            reader: b_reader,
+            writer: b_writer,
+        },
+    )
+}
+
+struct Pipe {
+    state: Mutex<PipeState>,
+    /// Notified when data was written or one of the ends was dropped
+    changed: Condvar,
+}
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    reader_closed: bool,
+    writer_closed: bool,
+}
+
+impl Pipe {
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, PipeState>> {
+        self.state
+            .lock()
+            .map_err(|_| io::Error::other("pipe is poisoned"))
+    }
+}
+
+/// Reading end of an in-memory [`pipe`]
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let state = self.pipe.lock()?;
+        let mut state = self
+            .pipe
+            .changed
+            .wait_while(state, |state| {
+                state.buffer.is_empty() && !state.writer_closed
+            })
+            .map_err(|_| io::Error::other("pipe is poisoned"))?;
+        let n = buf.len().min(state.buffer.len());
+        for (byte, b) in buf.iter_mut().zip(state.buffer.drain(..n)) {
+            *byte = b;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.pipe.lock() {
+            state.reader_closed = true;
+        }
+        self.pipe.changed.notify_all();
+    }
+}
+
+/// Writing end of an in-memory [`pipe`]
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.pipe.lock()?;
+        if state.reader_closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.buffer.extend(buf);
+        self.pipe.changed.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.pipe.lock() {
+            state.writer_closed = true;
+        }
+        self.pipe.changed.notify_all();
+    }
+}
+
+/// One end of an in-memory [`duplex`] pipe
+pub struct Duplex {
+    reader: PipeReader,
+    writer: PipeWriter,
+}
+
+impl Duplex {
+    /// Split into reader and writer, e.g. for [`crate::spawn_stream`]
+    pub fn split(self) -> (PipeReader, PipeWriter) {
+        (self.reader, self.writer)
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A fake process which follows a script of outputs, expected inputs and delays
+///
+/// The steps are run in order on a background thread. When all of them are done,
+/// the output is closed (the session reads EOF) and further input fails with
+/// [`io::ErrorKind::BrokenPipe`], just like when a process exits.
+///
+/// # Example
+///
+/// ```
+/// use rexpect::mock::Script;
+/// use rexpect::spawn_stream;
+/// use std::time::Duration;
+/// # use rexpect::error::Error;
+///
+/// # fn main() -> Result<(), Error> {
+/// let (reader, writer) = Script::new()
+///     .send("login: ")
+///     .expect("root\n")
+///     .delay(Duration::from_millis(50))
+///     .send("# ")
+///     .reply("exit\n", "logout\r\n")
+///     .spawn();
+/// let mut s = spawn_stream(reader, writer, Some(1000));
+/// s.exp_string("login: ")?;
+/// s.send_line("root")?;
+/// s.exp_string("# ")?;
+/// s.send_line("exit")?;
+/// assert_eq!("logout", s.read_line()?);
+/// s.exp_eof()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+enum Step {
+    Send(Vec<u8>),
+    Expect(Vec<u8>),
+    Delay(time::Duration),
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Write `output`, i.e. the session can read it
+    pub fn send(mut self, output: impl AsRef<[u8]>) -> Self {
+        self.steps.push(Step::Send(output.as_ref().to_vec()));
+        self
+    }
+
+    /// Wait until `input` was sent by the session
+    ///
+    /// Any input sent before `input` is skipped.
+    pub fn expect(mut self, input: impl AsRef<[u8]>) -> Self {
+        self.steps.push(Step::Expect(input.as_ref().to_vec()));
+        self
+    }
+
+    /// Wait for `duration`
+    pub fn delay(mut self, duration: time::Duration) -> Self {
+        self.steps.push(Step::Delay(duration));
+        self
+    }
+
+    /// When `input` was sent, answer with `output`
+    pub fn reply(self, input: impl AsRef<[u8]>, output: impl AsRef<[u8]>) -> Self {
+        self.expect(input).send(output)
+    }
+
+    /// Run the script, returns the reader and writer for the session
+    pub fn spawn(self) -> (PipeReader, PipeWriter) {
+        let (session, process) = duplex();
+        thread::spawn(move || self.run(process));
+        session.split()
+    }
+
+    fn run(self, mut process: Duplex) -> io::Result<()> {
+        let mut received = Vec::new();
+        for step in self.steps {
+            match step {
+                Step::Send(output) => process.write_all(&output)?,
+                Step::Expect(input) => loop {
+                    if let Some(pos) = find(&received, &input) {
+                        received.drain(..pos + input.len());
+                        break;
+                    }
+                    let mut buf = [0; 1024];
+                    let n = process.read(&mut buf)?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    received.extend_from_slice(&buf[..n]);
+                },
+                Step::Delay(duration) => thread::sleep(duration),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Position of the first occurrence of `needle` in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::reader::Options;
+    use crate::session::StreamSession;
+
+    #[test]
+    fn test_pipe() -> io::Result<()> {
+        let (mut reader, mut writer) = pipe();
+        writer.write_all(b"hello")?;
+        drop(writer);
+        let mut read = String::new();
+        reader.read_to_string(&mut read)?;
+        assert_eq!("hello", read);
+
+        let (reader, mut writer) = pipe();
+        drop(reader);
+        assert_eq!(
+            io::ErrorKind::BrokenPipe,
+            writer.write(b"hello").unwrap_err().kind()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_script() -> Result<(), Error> {
+        let (reader, writer) = Script::new()
+            .send("ready\r\n")
+            .reply("ping", "pong\r\n")
+            .delay(time::Duration::from_millis(50))
+            .send("bye\r\n")
+            .spawn();
+        let mut s = StreamSession::new(reader, writer, Options::new().timeout_ms(Some(1000)));
+        assert_eq!("ready", s.read_line()?);
+        s.send_line("ping")?;
+        assert_eq!("pong", s.read_line()?);
+        assert_eq!("bye", s.read_line()?);
+        s.exp_eof()?;
+        // the script is done, like an exited process
+        assert!(s.send_line("ping").is_err());
+        Ok(())
+    }
+}
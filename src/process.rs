@@ -2,25 +2,164 @@
 
 use crate::error::Error;
 use nix;
-use nix::fcntl::{OFlag, open};
-use nix::libc::STDERR_FILENO;
+use nix::errno::Errno;
+use nix::fcntl::{FcntlArg, OFlag, fcntl, open};
+use nix::libc::{self, STDERR_FILENO};
 use nix::pty::{PtyMaster, grantpt, posix_openpt, unlockpt};
-use nix::sys::{signal, wait};
+use nix::sys::{resource, signal, wait};
 use nix::sys::{stat, termios};
-use nix::unistd::{
-    ForkResult, Pid, close, dup, dup2_stderr, dup2_stdin, dup2_stdout, fork, setsid,
-};
+use nix::unistd::{ForkResult, Pid, close, dup2_stderr, dup2_stdin, dup2_stdout, fork, setsid};
 use std;
+use std::convert::Infallible;
 use std::fs::File;
-use std::io;
+use std::mem;
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd, RawFd};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::process::CommandExt;
-use std::process::Command;
-use std::{thread, time};
+use std::process::{ChildStdin, Command, Stdio};
+use std::sync::OnceLock;
+use std::{fmt, thread, time};
 
+pub use resource::Resource;
 pub use signal::Signal;
+pub use termios::Termios;
 pub use wait::WaitStatus;
 
+/// Limits and settings which are applied to the child of [`PtyProcess`] before it is executed
+///
+/// # Example
+///
+/// ```no_run
+/// use rexpect::process::{PtyProcess, SpawnOptions};
+/// use std::process::Command;
+///
+/// let options = SpawnOptions::new()
+///     .max_memory(512 * 1024 * 1024)
+///     .max_cpu_time(10)
+///     .max_core_size(0);
+/// let process = PtyProcess::with_options(Command::new("./fuzz-target"), options)
+///     .expect("could not execute fuzz-target");
+/// ```
+#[derive(Debug, Default)]
+pub struct SpawnOptions {
+    rlimits: Vec<(Resource, u64, u64)>,
+    passed_fds: Vec<(OwnedFd, RawFd)>,
+    #[cfg(target_os = "linux")]
+    parent_death_signal: Option<Signal>,
+    separate_stderr: bool,
+}
+
+impl SpawnOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the soft and hard limit of `resource`, see `setrlimit(2)`
+    pub fn rlimit(mut self, resource: Resource, soft: u64, hard: u64) -> Self {
+        self.rlimits.retain(|(r, _, _)| *r != resource);
+        self.rlimits.push((resource, soft, hard));
+        self
+    }
+
+    /// Limit the size of the virtual address space to `bytes` (`RLIMIT_AS`)
+    pub fn max_memory(self, bytes: u64) -> Self {
+        self.rlimit(Resource::RLIMIT_AS, bytes, bytes)
+    }
+
+    /// Limit the CPU time to `seconds` (`RLIMIT_CPU`)
+    ///
+    /// The child receives `SIGXCPU` once the limit is reached and `SIGKILL` one second later.
+    pub fn max_cpu_time(self, seconds: u64) -> Self {
+        self.rlimit(Resource::RLIMIT_CPU, seconds, seconds + 1)
+    }
+
+    /// Limit the number of open file descriptors to `n` (`RLIMIT_NOFILE`)
+    pub fn max_open_files(self, n: u64) -> Self {
+        self.rlimit(Resource::RLIMIT_NOFILE, n, n)
+    }
+
+    /// Limit the size of core dumps to `bytes` (`RLIMIT_CORE`), 0 disables them
+    pub fn max_core_size(self, bytes: u64) -> Self {
+        self.rlimit(Resource::RLIMIT_CORE, bytes, bytes)
+    }
+
+    /// Pass `fd` to the child as file descriptor number `target`
+    ///
+    /// Apart from stdin, stdout and stderr these are the only file descriptors which are
+    /// open in the child, all others are closed before it is executed.
+    /// `fd` itself is closed in the parent once the child was spawned.
+    pub fn pass_fd(mut self, fd: impl Into<OwnedFd>, target: RawFd) -> Self {
+        self.passed_fds.retain(|(_, t)| *t != target);
+        self.passed_fds.push((fd.into(), target));
+        self
+    }
+
+    /// Send `signal` to the child when the parent dies (`PR_SET_PDEATHSIG`)
+    ///
+    /// Note that "parent" is the thread which spawned the child:
+    /// the signal is also sent if that thread exits while the rest of the process keeps running.
+    #[cfg(target_os = "linux")]
+    pub fn parent_death_signal(mut self, signal: Signal) -> Self {
+        self.parent_death_signal = Some(signal);
+        self
+    }
+
+    /// Connect stderr of the child to a pipe of its own instead of the pty (or stdout pipe)
+    ///
+    /// Take its reading end with [`PtyProcess::take_stderr`] or [`PipeProcess::take_stderr`].
+    pub fn separate_stderr(mut self, yes: bool) -> Self {
+        self.separate_stderr = yes;
+        self
+    }
+
+    /// Set up `command` to apply the options in the child right before it is executed
+    ///
+    /// This runs after `command` set up its own stdio, so those fds are taken care of as well.
+    fn apply(self, command: &mut Command) {
+        let Self {
+            rlimits,
+            passed_fds,
+            #[cfg(target_os = "linux")]
+            parent_death_signal,
+            separate_stderr: _,
+        } = self;
+        #[cfg(target_os = "linux")]
+        let parent_pid = nix::unistd::getpid();
+        // nothing may be allocated in the child, as another thread might have held
+        // the lock of the allocator while forking
+        let mut keep: Vec<RawFd> = passed_fds.iter().map(|(_, target)| *target).collect();
+        keep.sort_unstable();
+        let mut moved = vec![0; passed_fds.len()];
+
+        // SAFETY: only file descriptors are duplicated, limits are set and signals are sent
+        unsafe {
+            command.pre_exec(move || {
+                if let Err(err) = install_fds(&passed_fds, &mut moved) {
+                    exit_child("cannot pass file descriptors", err);
+                }
+                close_on_exec_except(&keep);
+                for &(resource, soft, hard) in &rlimits {
+                    if let Err(err) = resource::setrlimit(resource, soft, hard) {
+                        exit_child("cannot set a resource limit", err);
+                    }
+                }
+
+                #[cfg(target_os = "linux")]
+                if let Some(signal) = parent_death_signal {
+                    if let Err(err) = nix::sys::prctl::set_pdeathsig(signal) {
+                        exit_child("cannot set the parent death signal", err);
+                    }
+                    // the parent might have died before the death signal was set up
+                    if nix::unistd::getppid() != parent_pid {
+                        let _ = signal::raise(signal);
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
 /// Start a process in a forked tty to interact with it like you would
 /// within a terminal
 ///
@@ -57,9 +196,10 @@ pub use wait::WaitStatus;
 /// # }
 /// ```
 pub struct PtyProcess {
+    // declared first, so that the process is killed before the pty is closed
+    child: ChildProcess,
     pty: PtyMaster,
-    pub(crate) child_pid: Pid,
-    kill_timeout: Option<time::Duration>,
+    stderr: Option<File>,
 }
 
 #[cfg(target_os = "linux")]
@@ -89,9 +229,22 @@ fn ptsname_r(fd: &PtyMaster) -> nix::Result<String> {
 
 impl PtyProcess {
     /// Start a process in a forked pty
-    pub fn new(mut command: Command) -> Result<Self, Error> {
-        // Open a new PTY master
-        let master_fd = posix_openpt(OFlag::O_RDWR)?;
+    pub fn new(command: Command) -> Result<Self, Error> {
+        Self::with_options(command, SpawnOptions::default())
+    }
+
+    /// Start a process in a forked pty, with the limits and settings of `options` applied to
+    /// the child before it is executed
+    pub fn with_options(mut command: Command, options: SpawnOptions) -> Result<Self, Error> {
+        // Open a new PTY master, it must not leak into any child process
+        #[cfg(target_os = "linux")]
+        let master_fd = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC)?;
+        #[cfg(not(target_os = "linux"))]
+        let master_fd = {
+            let fd = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+            fcntl(&fd, FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC))?;
+            fd
+        };
 
         // Allow a slave to be generated for it
         grantpt(&master_fd)?;
@@ -100,65 +253,87 @@ impl PtyProcess {
         // on Linux this is the libc function, on OSX this is our implementation of ptsname_r
         let slave_name = ptsname_r(&master_fd)?;
 
-        match unsafe { fork()? } {
-            ForkResult::Child => {
-                // Avoid leaking master fd
-                close(master_fd.as_raw_fd())?;
-
-                setsid()?; // create new session with child as session leader
-                let slave_fd = open(
-                    std::path::Path::new(&slave_name),
-                    OFlag::O_RDWR,
-                    stat::Mode::empty(),
-                )?;
-
-                // assign stdin, stdout, stderr to the tty, just like a terminal does
-                dup2_stdin(&slave_fd)?;
-                dup2_stdout(&slave_fd)?;
-                dup2_stderr(&slave_fd)?;
-
-                // Avoid leaking slave fd
-                if slave_fd.as_raw_fd() > STDERR_FILENO {
-                    close(slave_fd)?;
-                }
+        let stderr = match options.separate_stderr {
+            true => Some(cloexec_pipe()?),
+            false => None,
+        };
+
+        // closed in the child when it is executed (or exits)
+        let (exec_reader, exec_writer) = cloexec_pipe()?;
 
-                // set echo off
-                let stdin = io::stdin();
-                let mut flags = termios::tcgetattr(&stdin)?;
-                flags.local_flags &= !termios::LocalFlags::ECHO;
-                termios::tcsetattr(&stdin, termios::SetArg::TCSANOW, &flags)?;
+        let rlimits = options.rlimits.clone();
+        options.apply(&mut command);
 
-                let _ = command.exec();
-                Err(Error::Nix(nix::Error::last()))
+        match unsafe { fork()? } {
+            ForkResult::Child => {
+                // never return into the code of the parent, exit with a report instead
+                let Err(err) = exec_in_pty(command, master_fd, &slave_name, stderr);
+                exit_child("cannot execute the command in a pty", err)
+            }
+            ForkResult::Parent { child: child_pid } => {
+                let child = ChildProcess::new(child_pid, rlimits);
+                // the child turns echo off right before it is executed,
+                // input which is sent before would be echoed
+                drop(exec_writer);
+                while let Err(Errno::EINTR) = nix::unistd::read(&exec_reader, &mut [0]) {}
+                Ok(PtyProcess {
+                    child,
+                    pty: master_fd,
+                    stderr: stderr.map(|(reader, _)| reader.into()),
+                })
             }
-            ForkResult::Parent { child: child_pid } => Ok(PtyProcess {
-                pty: master_fd,
-                child_pid,
-                kill_timeout: None,
-            }),
         }
     }
 
     /// Get handle to pty fork for reading/writing
     pub fn get_file_handle(&self) -> Result<File, Error> {
         // needed because otherwise fd is closed both by dropping process and reader/writer
-        let fd = dup(&self.pty)?;
+        // (the duplicate is close-on-exec, so it doesn't leak into other spawned processes)
+        let fd = self.pty.as_fd().try_clone_to_owned()?;
         Ok(fd.into())
     }
 
+    /// Take the reading end of the stderr pipe of the process
+    ///
+    /// This is only available if the process was spawned with
+    /// [`SpawnOptions::separate_stderr`], otherwise stderr is written to the pty.
+    pub fn take_stderr(&mut self) -> Option<File> {
+        self.stderr.take()
+    }
+
+    /// Terminal attributes of the pty
+    pub fn termios(&self) -> Result<Termios, Error> {
+        termios::tcgetattr(&self.pty).map_err(Error::from)
+    }
+
+    /// Change the terminal attributes of the pty (immediately)
+    pub fn set_termios(&mut self, termios: &Termios) -> Result<(), Error> {
+        termios::tcsetattr(&self.pty, termios::SetArg::TCSANOW, termios).map_err(Error::from)
+    }
+
+    /// Window size of the pty as `(rows, columns)`
+    pub fn window_size(&self) -> Result<(u16, u16), Error> {
+        window_size(self.pty.as_fd()).map_err(Error::from)
+    }
+
+    /// Resize the pty, the process receives a `SIGWINCH`
+    pub fn set_window_size(&mut self, rows: u16, cols: u16) -> Result<(), Error> {
+        set_window_size(self.pty.as_fd(), rows, cols).map_err(Error::from)
+    }
+
     /// At the drop of `PtyProcess` the running process is killed (blocking).
     ///
     /// This is blocking forever if the process does not react to a normal kill.
     /// If `kill_timeout` is set the process is `kill -9`ed after duration.
     pub fn set_kill_timeout(&mut self, timeout_ms: Option<u64>) {
-        self.kill_timeout = timeout_ms.map(time::Duration::from_millis);
+        self.child.kill_timeout = timeout_ms.map(time::Duration::from_millis);
     }
 
     /// Get status of child process (non-blocking).
     ///
-    /// This method runs waitpid on the process:
-    /// if you ran `exit()` before or `status()` this method will
-    /// return `None`
+    /// This method runs waitpid on the process.
+    /// Once the process has terminated its exit status is remembered,
+    /// so later calls (as well as [`Self::wait`] and [`Self::exit`]) keep returning it.
     ///
     /// # Example
     /// ```rust,no_run
@@ -176,26 +351,33 @@ impl PtyProcess {
     /// ```
     ///
     pub fn status(&self) -> Option<WaitStatus> {
-        wait::waitpid(self.child_pid, Some(wait::WaitPidFlag::WNOHANG)).ok()
+        self.child.status()
+    }
+
+    /// Exit report of the child process if it has terminated (non-blocking).
+    ///
+    /// Returns `None` while the process is still running (or stopped).
+    pub fn exit_report(&self) -> Option<ExitReport> {
+        self.child.exit_report()
     }
 
-    /// Wait until process has exited (non-blocking).
+    /// Wait until process has exited (blocking).
     ///
     /// If the process doesn't terminate this will block forever.
-    pub fn wait(&self) -> Result<WaitStatus, Error> {
-        wait::waitpid(self.child_pid, None).map_err(Error::from)
+    pub fn wait(&self) -> Result<ExitReport, Error> {
+        self.child.wait()
     }
 
     /// Regularly exit the process (blocking).
     ///
     /// This method is blocking until the process is dead
-    pub fn exit(&mut self) -> Result<WaitStatus, Error> {
-        self.kill(signal::SIGTERM)
+    pub fn exit(&mut self) -> Result<ExitReport, Error> {
+        self.child.kill(signal::SIGTERM)
     }
 
     /// Kill the process with a specific signal (non-blocking).
     pub fn signal(&mut self, sig: Signal) -> Result<(), Error> {
-        signal::kill(self.child_pid, sig).map_err(Error::from)
+        self.child.signal(sig)
     }
 
     /// Kill the process with a specific signal (blocking).
@@ -208,37 +390,603 @@ impl PtyProcess {
     ///
     /// If `kill_timeout` is set and a repeated sending of signal does not result in the process
     /// being killed, then `kill -9` is sent after the `kill_timeout` duration has elapsed.
-    pub fn kill(&mut self, sig: Signal) -> Result<WaitStatus, Error> {
+    pub fn kill(&mut self, sig: Signal) -> Result<ExitReport, Error> {
+        self.child.kill(sig)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn child_pid(&self) -> Pid {
+        self.child.pid
+    }
+}
+
+/// The master side of the pty, e.g. to wait for output in an own event loop
+///
+/// Reading from it competes with the reader of a [`crate::session::PtySession`],
+/// so use [`crate::reader::Matcher`] to match the output instead.
+impl AsFd for PtyProcess {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.pty.as_fd()
+    }
+}
+
+impl AsRawFd for PtyProcess {
+    fn as_raw_fd(&self) -> RawFd {
+        self.pty.as_raw_fd()
+    }
+}
+
+#[cfg(feature = "mio")]
+#[cfg_attr(docsrs, doc(cfg(feature = "mio")))]
+impl mio::event::Source for PtyProcess {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+/// Start a process with stdin, stdout and stderr connected to pipes instead of a pty
+///
+/// This is useful to test how a program behaves when it doesn't run in a terminal.
+/// stdout and stderr write into the same pipe, so their output is interleaved
+/// in the order it was written (like with pexpect's `PopenSpawn`),
+/// unless [`SpawnOptions::separate_stderr`] is set.
+///
+/// The process is killed upon dropping `PipeProcess`
+pub struct PipeProcess {
+    child: ChildProcess,
+    stdin: Option<ChildStdin>,
+    output: Option<File>,
+    stderr: Option<File>,
+}
+
+impl PipeProcess {
+    /// Start a process connected to pipes
+    pub fn new(command: Command) -> Result<Self, Error> {
+        Self::with_options(command, SpawnOptions::default())
+    }
+
+    /// Start a process connected to pipes, with the limits and settings of `options` applied
+    /// to the child before it is executed
+    pub fn with_options(mut command: Command, options: SpawnOptions) -> Result<Self, Error> {
+        let (output, output_writer) = cloexec_pipe()?;
+        let stderr = match options.separate_stderr {
+            true => {
+                let (stderr, stderr_writer) = cloexec_pipe()?;
+                command.stderr(stderr_writer);
+                Some(stderr.into())
+            }
+            false => {
+                command.stderr(output_writer.try_clone()?);
+                None
+            }
+        };
+        command.stdin(Stdio::piped()).stdout(output_writer);
+
+        let rlimits = options.rlimits.clone();
+        options.apply(&mut command);
+        let mut child = command.spawn()?;
+        Ok(Self {
+            child: ChildProcess::new(Pid::from_raw(child.id() as libc::pid_t), rlimits),
+            stdin: child.stdin.take(),
+            output: Some(output.into()),
+            stderr,
+        })
+    }
+
+    /// Take the writing end of the stdin pipe of the process
+    ///
+    /// Dropping it closes stdin, i.e. the process reads EOF.
+    pub fn take_stdin(&mut self) -> Option<ChildStdin> {
+        self.stdin.take()
+    }
+
+    /// Take the reading end of the pipe which stdout and stderr of the process write to
+    ///
+    /// With [`SpawnOptions::separate_stderr`] only stdout is written to it.
+    pub fn take_output(&mut self) -> Option<File> {
+        self.output.take()
+    }
+
+    /// Take the reading end of the stderr pipe of the process
+    ///
+    /// This is only available if the process was spawned with
+    /// [`SpawnOptions::separate_stderr`].
+    pub fn take_stderr(&mut self) -> Option<File> {
+        self.stderr.take()
+    }
+
+    /// See [`PtyProcess::set_kill_timeout`]
+    pub fn set_kill_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.child.kill_timeout = timeout_ms.map(time::Duration::from_millis);
+    }
+
+    /// See [`PtyProcess::status`]
+    pub fn status(&self) -> Option<WaitStatus> {
+        self.child.status()
+    }
+
+    /// See [`PtyProcess::exit_report`]
+    pub fn exit_report(&self) -> Option<ExitReport> {
+        self.child.exit_report()
+    }
+
+    /// See [`PtyProcess::wait`]
+    pub fn wait(&self) -> Result<ExitReport, Error> {
+        self.child.wait()
+    }
+
+    /// See [`PtyProcess::exit`]
+    pub fn exit(&mut self) -> Result<ExitReport, Error> {
+        self.child.kill(signal::SIGTERM)
+    }
+
+    /// See [`PtyProcess::signal`]
+    pub fn signal(&mut self, sig: Signal) -> Result<(), Error> {
+        self.child.signal(sig)
+    }
+
+    /// See [`PtyProcess::kill`]
+    pub fn kill(&mut self, sig: Signal) -> Result<ExitReport, Error> {
+        self.child.kill(sig)
+    }
+}
+
+/// Process control shared by [`PtyProcess`] and [`PipeProcess`]
+///
+/// The process is killed upon dropping `ChildProcess`
+struct ChildProcess {
+    pid: Pid,
+    kill_timeout: Option<time::Duration>,
+    exit_report: OnceLock<ExitReport>,
+    rlimits: Vec<(Resource, u64, u64)>,
+}
+
+impl ChildProcess {
+    fn new(pid: Pid, rlimits: Vec<(Resource, u64, u64)>) -> Self {
+        Self {
+            pid,
+            kill_timeout: None,
+            exit_report: OnceLock::new(),
+            rlimits,
+        }
+    }
+
+    fn status(&self) -> Option<WaitStatus> {
+        if let Some(report) = self.exit_report.get() {
+            return Some(report.status());
+        }
+        self.wait4(Some(wait::WaitPidFlag::WNOHANG)).ok()
+    }
+
+    fn exit_report(&self) -> Option<ExitReport> {
+        self.status()?;
+        self.exit_report.get().copied()
+    }
+
+    fn wait(&self) -> Result<ExitReport, Error> {
+        loop {
+            if let Some(report) = self.exit_report.get() {
+                return Ok(*report);
+            }
+            self.wait4(None)?;
+        }
+    }
+
+    /// `waitpid` which also collects the resource usage of the process
+    ///
+    /// The exit report is remembered as soon as the process is gone.
+    fn wait4(&self, options: Option<wait::WaitPidFlag>) -> nix::Result<WaitStatus> {
+        let mut status = 0;
+        let mut usage = mem::MaybeUninit::<libc::rusage>::zeroed();
+        let options = options.map(|o| o.bits()).unwrap_or(0);
+        // SAFETY: both pointers are valid for writes for the duration of the call
+        let pid =
+            unsafe { libc::wait4(self.pid.as_raw(), &mut status, options, usage.as_mut_ptr()) };
+        match Errno::result(pid)? {
+            0 => Ok(WaitStatus::StillAlive),
+            pid => {
+                let status = WaitStatus::from_raw(Pid::from_raw(pid), status)?;
+                if let WaitStatus::Exited(..) | WaitStatus::Signaled(..) = status {
+                    // SAFETY: the kernel filled out the usage of the reaped process
+                    let usage = unsafe { usage.assume_init() };
+                    let report = ExitReport::new(status, &usage, &self.rlimits);
+                    let _ = self.exit_report.set(report);
+                }
+                Ok(status)
+            }
+        }
+    }
+
+    fn signal(&self, sig: Signal) -> Result<(), Error> {
+        signal::kill(self.pid, sig).map_err(Error::from)
+    }
+
+    fn kill(&self, sig: Signal) -> Result<ExitReport, Error> {
+        // the pid may already be reused by another process
+        if let Some(report) = self.exit_report.get() {
+            return Ok(*report);
+        }
         let start = time::Instant::now();
         loop {
-            match signal::kill(self.child_pid, sig) {
+            match signal::kill(self.pid, sig) {
                 Ok(_) => {}
-                // process was already killed before -> ignore
-                Err(nix::errno::Errno::ESRCH) => {
-                    return Ok(WaitStatus::Exited(Pid::from_raw(0), 0));
+                // the process is gone, it may have been reaped behind our back
+                Err(Errno::ESRCH) => {
+                    return self.exit_report().ok_or(Error::Nix(Errno::ESRCH));
                 }
                 Err(e) => return Err(Error::from(e)),
             }
 
-            match self.status() {
-                Some(status) if status != WaitStatus::StillAlive => return Ok(status),
-                Some(_) | None => thread::sleep(time::Duration::from_millis(100)),
+            match self.exit_report() {
+                Some(report) => return Ok(report),
+                None => thread::sleep(time::Duration::from_millis(100)),
             }
             // kill -9 if timeout is reached
             if let Some(timeout) = self.kill_timeout {
                 if start.elapsed() > timeout {
-                    signal::kill(self.child_pid, Signal::SIGKILL).map_err(Error::from)?;
+                    signal::kill(self.pid, Signal::SIGKILL).map_err(Error::from)?;
                 }
             }
         }
     }
 }
 
-impl Drop for PtyProcess {
+impl Drop for ChildProcess {
     fn drop(&mut self) {
         if let Some(WaitStatus::StillAlive) = self.status() {
-            self.exit().expect("cannot exit");
+            match self.kill(signal::SIGTERM) {
+                // reaped elsewhere in the meantime
+                Ok(_) | Err(Error::Nix(Errno::ESRCH)) => {}
+                Err(err) => panic!("cannot exit: {err}"),
+            }
+        }
+    }
+}
+
+/// How a child process terminated, together with the resources it used
+///
+/// Returned by [`PtyProcess::wait`], [`PtyProcess::exit`] and [`PtyProcess::kill`]
+/// (as well as the same methods of [`PipeProcess`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExitReport {
+    status: WaitStatus,
+    user_time: time::Duration,
+    system_time: time::Duration,
+    max_rss: u64,
+    limit_exceeded: Option<Resource>,
+    limit_suspected: Option<Resource>,
+}
+
+impl ExitReport {
+    fn new(status: WaitStatus, usage: &libc::rusage, rlimits: &[(Resource, u64, u64)]) -> Self {
+        // ru_maxrss is in kilobytes on Linux but in bytes on macOS
+        let rss_unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+        let mut report = Self {
+            status,
+            user_time: timeval_to_duration(&usage.ru_utime),
+            system_time: timeval_to_duration(&usage.ru_stime),
+            max_rss: u64::try_from(usage.ru_maxrss).unwrap_or(0) * rss_unit,
+            limit_exceeded: None,
+            limit_suspected: None,
+        };
+        let cpu_time = (report.user_time + report.system_time).as_secs();
+        report.limit_exceeded = match report.signal() {
+            Some(Signal::SIGXCPU) => Some(Resource::RLIMIT_CPU),
+            Some(Signal::SIGXFSZ) => Some(Resource::RLIMIT_FSIZE),
+            // the hard CPU limit is enforced with SIGKILL
+            Some(Signal::SIGKILL) => rlimits
+                .iter()
+                .find(|&&(r, _, hard)| r == Resource::RLIMIT_CPU && cpu_time >= hard)
+                .map(|&(r, _, _)| r),
+            _ => None,
+        };
+        if report.limit_exceeded.is_none() && !report.success() {
+            let is_set = |resource| rlimits.iter().any(|&(r, _, _)| r == resource);
+            // running out of memory usually ends with a crash or an abort
+            let crashed = matches!(
+                report.signal(),
+                Some(Signal::SIGSEGV | Signal::SIGBUS | Signal::SIGABRT)
+            );
+            report.limit_suspected = if is_set(Resource::RLIMIT_AS) && crashed {
+                Some(Resource::RLIMIT_AS)
+            } else if is_set(Resource::RLIMIT_NOFILE) && report.exit_code().is_some() {
+                Some(Resource::RLIMIT_NOFILE)
+            } else if is_set(Resource::RLIMIT_AS) && report.exit_code().is_some() {
+                Some(Resource::RLIMIT_AS)
+            } else {
+                None
+            };
+        }
+        report
+    }
+
+    /// The raw status as returned by `waitpid`
+    pub fn status(&self) -> WaitStatus {
+        self.status
+    }
+
+    /// Exit code if the process exited regularly
+    pub fn exit_code(&self) -> Option<i32> {
+        match self.status {
+            WaitStatus::Exited(_, code) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// Signal which terminated the process
+    pub fn signal(&self) -> Option<Signal> {
+        match self.status {
+            WaitStatus::Signaled(_, signal, _) => Some(signal),
+            _ => None,
+        }
+    }
+
+    /// Whether the process dumped core when it was terminated by a signal
+    pub fn core_dumped(&self) -> bool {
+        matches!(self.status, WaitStatus::Signaled(_, _, true))
+    }
+
+    /// Whether the process exited with code 0
+    pub fn success(&self) -> bool {
+        self.exit_code() == Some(0)
+    }
+
+    /// CPU time spent in user mode
+    pub fn user_time(&self) -> time::Duration {
+        self.user_time
+    }
+
+    /// CPU time spent in kernel mode
+    pub fn system_time(&self) -> time::Duration {
+        self.system_time
+    }
+
+    /// Maximum resident set size in bytes
+    pub fn max_rss(&self) -> u64 {
+        self.max_rss
+    }
+
+    /// The resource limit (see [`SpawnOptions`]) which got the process killed
+    ///
+    /// Only limits which are enforced by a signal are detected, i.e. CPU time and file size.
+    pub fn limit_exceeded(&self) -> Option<Resource> {
+        self.limit_exceeded
+    }
+
+    /// The resource limit which the process probably ran into, if it failed
+    ///
+    /// Exceeding the memory (`RLIMIT_AS`) or open files (`RLIMIT_NOFILE`) limit isn't reported
+    /// by the kernel, allocating memory or opening a file just fails. So this is a guess:
+    /// one of these limits was set and the process exited with an error, or crashed
+    /// in case of the memory limit. Unlike [`Self::limit_exceeded`], the guess isn't
+    /// part of the `Display` output, as the process may just as well have failed
+    /// for another reason.
+    pub fn limit_suspected(&self) -> Option<Resource> {
+        self.limit_suspected
+    }
+}
+
+impl From<WaitStatus> for ExitReport {
+    /// Exit report without any resource usage
+    fn from(status: WaitStatus) -> Self {
+        Self {
+            status,
+            user_time: time::Duration::ZERO,
+            system_time: time::Duration::ZERO,
+            max_rss: 0,
+            limit_exceeded: None,
+            limit_suspected: None,
+        }
+    }
+}
+
+impl fmt::Display for ExitReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            WaitStatus::Exited(_, code) => write!(f, "exit code {code}")?,
+            WaitStatus::Signaled(_, signal, core_dumped) => {
+                write!(f, "signal {signal}")?;
+                if core_dumped {
+                    write!(f, " (core dumped)")?;
+                }
+            }
+            status => return write!(f, "{status:?}"),
+        }
+        match self.limit_exceeded {
+            None => Ok(()),
+            Some(Resource::RLIMIT_CPU) => write!(f, ", CPU time limit exceeded"),
+            Some(Resource::RLIMIT_FSIZE) => write!(f, ", file size limit exceeded"),
+            Some(resource) => write!(f, ", {resource:?} exceeded"),
+        }
+    }
+}
+
+/// The child side of [`PtyProcess::with_options`], only returns if something failed
+fn exec_in_pty(
+    mut command: Command,
+    master_fd: PtyMaster,
+    slave_name: &str,
+    stderr: Option<(OwnedFd, OwnedFd)>,
+) -> nix::Result<Infallible> {
+    // Avoid leaking master fd
+    drop(master_fd);
+
+    setsid()?; // create new session with child as session leader
+    let slave_fd = open(
+        std::path::Path::new(slave_name),
+        OFlag::O_RDWR,
+        stat::Mode::empty(),
+    )?;
+
+    // assign stdin, stdout, stderr to the tty, just like a terminal does
+    dup2_stdin(&slave_fd)?;
+    dup2_stdout(&slave_fd)?;
+    match stderr {
+        Some((_, stderr_writer)) => dup2_stderr(stderr_writer)?,
+        None => dup2_stderr(&slave_fd)?,
+    }
+
+    // Avoid leaking slave fd
+    if slave_fd.as_raw_fd() > STDERR_FILENO {
+        close(slave_fd)?;
+    }
+
+    // set echo off
+    let stdin = std::io::stdin();
+    let mut flags = termios::tcgetattr(&stdin)?;
+    flags.local_flags &= !termios::LocalFlags::ECHO;
+    termios::tcsetattr(&stdin, termios::SetArg::TCSANOW, &flags)?;
+
+    let err = command.exec();
+    Err(err
+        .raw_os_error()
+        .map(Errno::from_raw)
+        .unwrap_or(Errno::UnknownErrno))
+}
+
+/// Write why the child couldn't be set up to its stderr and exit with code 127,
+/// like a shell does if it can't execute a command
+///
+/// Only async-signal-safe functions are used, as this runs between `fork` and `exec`.
+fn exit_child(reason: &str, err: Errno) -> ! {
+    for part in ["rexpect: ", reason, ": ", err.desc(), "\n"] {
+        // SAFETY: the buffer is valid for the duration of the call
+        let _ = unsafe { libc::write(STDERR_FILENO, part.as_ptr().cast(), part.len()) };
+    }
+    // SAFETY: exits without running the destructors or atexit handlers of the parent
+    unsafe { libc::_exit(127) }
+}
+
+/// Create a pipe whose ends are not inherited by spawned processes
+pub(crate) fn cloexec_pipe() -> nix::Result<(OwnedFd, OwnedFd)> {
+    #[cfg(target_os = "linux")]
+    return nix::unistd::pipe2(OFlag::O_CLOEXEC);
+    #[cfg(not(target_os = "linux"))]
+    {
+        let (r, w) = nix::unistd::pipe()?;
+        for fd in [&r, &w] {
+            fcntl(fd, FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC))?;
+        }
+        Ok((r, w))
+    }
+}
+
+fn timeval_to_duration(tv: &libc::timeval) -> time::Duration {
+    time::Duration::new(
+        u64::try_from(tv.tv_sec).unwrap_or(0),
+        u32::try_from(tv.tv_usec).unwrap_or(0) * 1000,
+    )
+}
+
+/// Window size of the terminal `fd` as `(rows, columns)`
+pub(crate) fn window_size(fd: BorrowedFd<'_>) -> nix::Result<(u16, u16)> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ writes a winsize, which `size` has room for
+    Errno::result(unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCGWINSZ, &mut size) })?;
+    Ok((size.ws_row, size.ws_col))
+}
+
+/// Resize the terminal `fd`
+pub(crate) fn set_window_size(fd: BorrowedFd<'_>, rows: u16, cols: u16) -> nix::Result<()> {
+    let size = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCSWINSZ only reads the winsize
+    Errno::result(unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCSWINSZ, &size) })?;
+    Ok(())
+}
+
+/// Duplicate each fd to its target number
+///
+/// All fds are moved out of the way first (to `moved`, which has the same length as `fds`),
+/// so that sources and targets may overlap.
+fn install_fds(fds: &[(OwnedFd, RawFd)], moved: &mut [RawFd]) -> nix::Result<()> {
+    let above = fds
+        .iter()
+        .flat_map(|(fd, target)| [fd.as_raw_fd(), *target])
+        .max()
+        .unwrap_or(STDERR_FILENO)
+        + 1;
+    for ((fd, _), tmp) in fds.iter().zip(moved.iter_mut()) {
+        *tmp = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(above))?;
+    }
+    for ((_, target), &tmp) in fds.iter().zip(moved.iter()) {
+        // SAFETY: dup2 doesn't touch any memory, the duplicate is not close-on-exec
+        Errno::result(unsafe { libc::dup2(tmp, *target) })?;
+    }
+    Ok(())
+}
+
+/// Mark all file descriptors except stdin, stdout, stderr and `keep` (which must be sorted)
+/// as close-on-exec
+///
+/// They are not closed right away, as `Command` might still need some of them until `exec`.
+fn close_on_exec_except(keep: &[RawFd]) {
+    let mut first = STDERR_FILENO + 1;
+    for &fd in keep {
+        if fd >= first {
+            close_on_exec_range(first, Some(fd - 1));
+            first = fd + 1;
+        }
+    }
+    close_on_exec_range(first, None);
+}
+
+/// Mark file descriptors from `first` to `last` (inclusive, or all if `None`) as close-on-exec
+fn close_on_exec_range(first: RawFd, last: Option<RawFd>) {
+    if last.is_some_and(|last| last < first) {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let last = last.map(|l| l as libc::c_uint).unwrap_or(libc::c_uint::MAX);
+        // SAFETY: close_range doesn't touch any memory
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_close_range,
+                first as libc::c_uint,
+                last,
+                libc::CLOSE_RANGE_CLOEXEC,
+            )
+        };
+        if res == 0 {
+            return;
         }
+        // CLOSE_RANGE_CLOEXEC is available since Linux 5.11, fall back to one by one
+    }
+    let max = resource::getrlimit(Resource::RLIMIT_NOFILE)
+        .map(|(soft, _)| RawFd::try_from(soft).unwrap_or(RawFd::MAX))
+        .unwrap_or(1024);
+    let last = last.unwrap_or(max).min(max);
+    for fd in first..=last {
+        // SAFETY: F_SETFD doesn't touch any memory, fds which are not open are ignored
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
     }
 }
 
@@ -246,7 +994,7 @@ impl Drop for PtyProcess {
 mod tests {
     use super::*;
     use nix::sys::wait;
-    use std::io::{BufRead, BufReader, LineWriter, Write};
+    use std::io::{self, BufRead, BufReader, LineWriter, Write};
 
     #[test]
     /// Open cat, write string, read back string twice, send Ctrl^C and check that cat exited
@@ -265,8 +1013,95 @@ mod tests {
         thread::sleep(time::Duration::from_millis(100));
         writer.write_all(&[3])?; // send ^C
         writer.flush()?;
-        let should = WaitStatus::Signaled(process.child_pid, Signal::SIGINT, false);
-        assert_eq!(should, wait::waitpid(process.child_pid, None).unwrap());
+        let should = WaitStatus::Signaled(process.child.pid, Signal::SIGINT, false);
+        assert_eq!(should, wait::waitpid(process.child.pid, None).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_report_usage() -> Result<(), Error> {
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "i=0; while [ $i -lt 100000 ]; do i=$((i+1)); done; head -c 20000000 /dev/zero >/dev/null",
+        ]);
+        let process = PtyProcess::new(command)?;
+        let report = process.wait()?;
+        assert!(report.success());
+        assert_eq!("exit code 0", report.to_string());
+        // the loop takes a while, the reading may be too short to be accounted
+        assert!(report.user_time() > time::Duration::ZERO);
+        // sh alone needs more than 100 KiB
+        assert!(
+            report.max_rss() > 100 * 1024,
+            "max_rss {}",
+            report.max_rss()
+        );
+        assert_eq!(None, report.limit_exceeded());
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_report_core_dumped() -> Result<(), Error> {
+        let report = ExitReport::from(WaitStatus::Signaled(
+            Pid::from_raw(1),
+            Signal::SIGSEGV,
+            true,
+        ));
+        assert!(report.core_dumped());
+        assert_eq!("signal SIGSEGV (core dumped)", report.to_string());
+
+        // the core file is written to the working directory of the process
+        let dir = tempfile::tempdir()?;
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", "kill -SEGV $$"])
+            .current_dir(dir.path());
+        let options = SpawnOptions::new().max_core_size(resource::RLIM_INFINITY);
+        let report = PtyProcess::with_options(command, options)?.wait()?;
+        assert_eq!(Some(Signal::SIGSEGV), report.signal());
+        assert_eq!(None, report.exit_code());
+        // whether a core is dumped depends on the core_pattern of the host
+        assert!(report.to_string().starts_with("signal SIGSEGV"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_limit_suspected() -> Result<(), Error> {
+        let mut command = Command::new("sh");
+        command.args(["-c", "a=$(yes | head -c 100000000)"]);
+        let options = SpawnOptions::new().max_memory(50 * 1024 * 1024);
+        let report = PtyProcess::with_options(command, options)?.wait()?;
+        assert_eq!(None, report.limit_exceeded());
+        assert_eq!(Some(Resource::RLIMIT_AS), report.limit_suspected());
+        // only a guess, which isn't reported as the reason
+        assert!(!report.to_string().contains("limit"));
+
+        // any failure is suspected, whether a process runs into the limit depends on
+        // how it is built, e.g. loading shared libraries needs file descriptors
+        let mut command = Command::new("sh");
+        command.args(["-c", "exit 3"]);
+        let options = SpawnOptions::new().max_open_files(4);
+        let report = PtyProcess::with_options(command, options)?.wait()?;
+        assert_eq!(Some(Resource::RLIMIT_NOFILE), report.limit_suspected());
+
+        let report =
+            PtyProcess::with_options(Command::new("true"), SpawnOptions::new().max_open_files(4))?
+                .wait()?;
+        assert_eq!(None, report.limit_suspected());
+        Ok(())
+    }
+
+    #[test]
+    fn test_kill_reaped_process() -> Result<(), Error> {
+        let mut process = PtyProcess::new(Command::new("cat"))?;
+        signal::kill(process.child.pid, Signal::SIGKILL)?;
+        wait::waitpid(process.child.pid, None)?;
+        // the exit status was collected by someone else, so there is no report
+        assert!(matches!(
+            process.kill(Signal::SIGTERM),
+            Err(Error::Nix(Errno::ESRCH))
+        ));
         Ok(())
     }
 }
@@ -1,13 +1,28 @@
 //! Unblocking reader which supports waiting for strings/regexes and EOF to be present
 
+use crate::asciicast::Asciicast;
 use crate::error::Error;
+use crate::log::{Direction, Log, Record, Transcript};
+use crate::process::{PtyProcess, cloexec_pipe};
+use crate::terminal::{Responder, TerminalProfile};
+use crate::writer::WaitingWriter;
+use nix::errno::Errno;
+use nix::fcntl::{FcntlArg, OFlag, fcntl};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
 pub use regex::Regex;
+use std::fs::File;
 use std::io::prelude::*;
 use std::io::{self, BufReader};
-use std::sync::mpsc::{Receiver, channel};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError, channel};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::{fmt, time};
 
+/// How long a reply to a terminal query waits for the process to accept it, if there is no
+/// write timeout
+const REPLY_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
 /// Options for [`NBReader`]
 #[derive(Default)]
 pub struct Options {
@@ -17,6 +32,33 @@ pub struct Options {
     pub(crate) timeout_ms: Option<u64>,
     /// Whether to filter out escape codes, such as colors.
     pub(crate) strip_ansi_escape_codes: bool,
+    /// Whether to read in the calling thread instead of a background thread
+    pub(crate) poll_reader: bool,
+    /// `None`: writing blocks until the process accepts the input
+    ///
+    /// `Some(millis)`: writing fails if the process doesn't accept input for millis milliseconds
+    pub(crate) write_timeout_ms: Option<u64>,
+    /// Whether the input is queued for a background thread which writes it
+    pub(crate) writer_thread: bool,
+    /// How long to wait before sending
+    pub(crate) delay_before_send_ms: Option<u64>,
+    /// Delay and maximal jitter between the characters, if the input is typed
+    pub(crate) typing_delay_ms: Option<(u64, u64)>,
+    /// Terminal type, which determines the encoding of special keys
+    pub(crate) term: Option<String>,
+    /// Terminal which answers the queries of a process in a pty
+    pub(crate) terminal_profile: Option<TerminalProfile>,
+    /// Whether the modes which the process turns on in a pty are followed
+    pub(crate) track_terminal_modes: bool,
+    /// Where the output of the process is logged to
+    pub(crate) log_read: Option<Box<dyn Write + Send>>,
+    /// Where the input sent to the process is logged to
+    pub(crate) log_send: Option<Box<dyn Write + Send>>,
+    /// Recorders of both the output and the input
+    pub(crate) recorders: Vec<Arc<Mutex<dyn Record>>>,
+    /// Environment variables of the spawned process which the recorders note,
+    /// `None` if the process wasn't spawned by us
+    pub(crate) recorded_env: Option<Vec<(String, String)>>,
 }
 
 impl Options {
@@ -33,95 +75,414 @@ impl Options {
         self.strip_ansi_escape_codes = yes;
         self
     }
+
+    /// Read the output in the calling thread by polling the file descriptor,
+    /// instead of spawning a background thread which reads ahead
+    ///
+    /// This is used by the sessions which read from a file descriptor, e.g. [`crate::spawn`],
+    /// see [`NBReader::from_fd`].
+    pub fn poll_reader(mut self, yes: bool) -> Self {
+        self.poll_reader = yes;
+        self
+    }
+
+    /// Fail with [`io::ErrorKind::TimedOut`] if the process doesn't accept input
+    /// for `timeout_ms`, instead of blocking forever once the input buffer is full
+    ///
+    /// Once the process accepts input, it is written in chunks. A pty may have room for less
+    /// than a chunk (1 KiB), then writing it blocks until the process read the rest.
+    ///
+    /// This is used by the sessions which write to a file descriptor, e.g. [`crate::spawn`].
+    /// With [`Self::writer_thread`] this limits how long flushing waits for the queue instead.
+    pub fn write_timeout_ms(mut self, timeout_ms: Option<u64>) -> Self {
+        self.write_timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Queue the input for a background thread instead of writing it directly,
+    /// so sending large inputs doesn't block while the process doesn't read them
+    ///
+    /// Sending returns right away, flushing waits until the queue is written.
+    /// Errors of the thread are returned by the next send.
+    /// This is used by the sessions which write to a file descriptor, e.g. [`crate::spawn`].
+    pub fn writer_thread(mut self, yes: bool) -> Self {
+        self.writer_thread = yes;
+        self
+    }
+
+    /// Wait `delay_ms` before each send (like pexpect's `delaybeforesend`)
+    ///
+    /// Some programs lose input which arrives before they are ready for it,
+    /// e.g. right after printing a prompt.
+    pub fn delay_before_send_ms(mut self, delay_ms: Option<u64>) -> Self {
+        self.delay_before_send_ms = delay_ms;
+        self
+    }
+
+    /// Type the input one character at a time, each followed by a pause of `delay_ms`
+    /// plus a random jitter of up to `jitter_ms`
+    ///
+    /// This is for programs which lose or reorder input which arrives all at once.
+    pub fn typing_delay_ms(mut self, delay_ms: Option<u64>, jitter_ms: u64) -> Self {
+        self.typing_delay_ms = delay_ms.map(|delay_ms| (delay_ms, jitter_ms));
+        self
+    }
+
+    /// Terminal type to emulate, e.g. `xterm-256color`
+    ///
+    /// This determines how special keys are encoded, see [`crate::keys::Key::encode`].
+    /// A process spawned in a pty gets it as `TERM`. By default the `TERM` of such
+    /// a process is used, otherwise xterm is assumed.
+    pub fn term(mut self, term: impl Into<String>) -> Self {
+        self.term = Some(term.into());
+        self
+    }
+
+    /// Answer the queries a process sends to its terminal, e.g. for the cursor position,
+    /// as the terminal `profile` would
+    ///
+    /// Without a terminal behind the pty nothing answers them, so processes which ask
+    /// (like vim, fish or apps using crossterm) wait for a reply or give up after a while.
+    /// The queries are answered as soon as they are read, see [`TerminalProfile`]
+    /// for which ones. This only applies to processes spawned in a pty.
+    ///
+    /// The replies are written to the pty by whoever reads the output (the reader thread,
+    /// or the caller while waiting for output), not by the session's writer. Each reply is
+    /// written at once, but it may come between two writes of input which is sent at the
+    /// same time, e.g. between typed characters. A reply which the process doesn't accept
+    /// within the write timeout (by default a second) is dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rexpect::reader::Options;
+    /// use rexpect::spawn_with_options;
+    /// use rexpect::terminal::TerminalProfile;
+    /// use std::process::Command;
+    /// # use rexpect::error::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let mut command = Command::new("sh");
+    /// // print some text, ask for the cursor position and print the reply
+    /// command.args(["-c", r"stty raw -echo; printf 'abc\033[6n'; head -c 6 | tr '\033' E"]);
+    /// let options = Options::new()
+    ///     .timeout_ms(Some(1000))
+    ///     .answer_terminal_queries(TerminalProfile::new());
+    /// let mut p = spawn_with_options(command, options)?;
+    /// p.exp_string("abc")?;
+    /// p.exp_string("E[1;4R")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn answer_terminal_queries(mut self, profile: TerminalProfile) -> Self {
+        self.terminal_profile = Some(profile);
+        self
+    }
+
+    /// Follow the input modes which the process turns on in its output, e.g. bracketed paste
+    ///
+    /// This is needed by [`crate::session::PtySession::send_paste`] and
+    /// [`crate::session::PtySession::send_mouse`]. The output is parsed for it while it is read.
+    pub fn track_terminal_modes(mut self, yes: bool) -> Self {
+        self.track_terminal_modes = yes;
+        self
+    }
+
+    /// Log everything which is read from the process to `writer` (like pexpect's `logfile_read`)
+    ///
+    /// The data is logged as it was read, i.e. before escape codes are stripped.
+    pub fn log_read(mut self, writer: impl Write + Send + 'static) -> Self {
+        self.log_read = Some(Box::new(writer));
+        self
+    }
+
+    /// Log everything which is sent to the process to `writer` (like pexpect's `logfile_send`)
+    pub fn log_send(mut self, writer: impl Write + Send + 'static) -> Self {
+        self.log_send = Some(Box::new(writer));
+        self
+    }
+
+    /// Log everything which is read from and sent to the process to `writer`
+    ///
+    /// Each line is prefixed with `< ` if it was read and `> ` if it was sent,
+    /// a change of direction always starts a new line:
+    ///
+    /// ```text
+    /// > echo hello
+    /// < hello
+    /// < $
+    /// ```
+    pub fn log(mut self, writer: impl Write + Send + 'static) -> Self {
+        let transcript = Transcript::new(Box::new(writer));
+        self.recorders.push(Arc::new(Mutex::new(transcript)));
+        self
+    }
+
+    /// Record the session in asciicast v2 format to `writer`, to be replayed with asciinema
+    ///
+    /// Output of the process is recorded as `"o"` events, input sent to it as `"i"` events.
+    /// The header contains the window size of the pty (or 80x24 if there is none)
+    /// and `SHELL` and `TERM` of the environment.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rexpect::reader::Options;
+    /// use rexpect::session::spawn_bash_with_options;
+    /// # use rexpect::error::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let cast = std::fs::File::create("session.cast")?;
+    /// let mut p = spawn_bash_with_options(
+    ///     Options::new().timeout_ms(Some(1000)).record_asciicast(cast),
+    /// )?;
+    /// p.execute("ls", "Cargo.toml")?;
+    /// // replay with `asciinema play session.cast`
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn record_asciicast(mut self, writer: impl Write + Send + 'static) -> Self {
+        let asciicast = Asciicast::new(Box::new(writer));
+        self.recorders.push(Arc::new(Mutex::new(asciicast)));
+        self
+    }
+
+    pub(crate) fn take_read_log(&mut self) -> Log {
+        Log::new(
+            Direction::Read,
+            self.log_read.take(),
+            self.recorders.clone(),
+        )
+    }
+
+    pub(crate) fn take_send_log(&mut self) -> Log {
+        Log::new(
+            Direction::Send,
+            self.log_send.take(),
+            self.recorders.clone(),
+        )
+    }
+
+    /// Let a [`Responder`] answer the queries of `process`, if a profile was set
+    pub(crate) fn start_responder(&mut self, process: &PtyProcess) -> Result<(), Error> {
+        if let Some(profile) = self.terminal_profile.take() {
+            let timeout = self.write_timeout_ms.map(time::Duration::from_millis);
+            let input =
+                WaitingWriter::new(process.get_file_handle()?, timeout.unwrap_or(REPLY_TIMEOUT));
+            let responder = Responder::new(profile, input);
+            self.recorders.push(Arc::new(Mutex::new(responder)));
+        }
+        Ok(())
+    }
+
+    /// Tell the recorders that the session starts, `window_size` is (rows, columns)
+    pub(crate) fn start_recording(&self, window_size: Option<(u16, u16)>) {
+        for recorder in &self.recorders {
+            if let Ok(mut recorder) = recorder.lock() {
+                let _ = recorder.start(window_size, self.recorded_env.as_deref());
+            }
+        }
+    }
+
+    /// Copy of the options which concern reading, i.e. without the logs and recorders,
+    /// e.g. for the reader of stderr
+    pub(crate) fn reader_options(&self) -> Self {
+        Self {
+            timeout_ms: self.timeout_ms,
+            strip_ansi_escape_codes: self.strip_ansi_escape_codes,
+            poll_reader: self.poll_reader,
+            ..Default::default()
+        }
+    }
 }
 
 /// Non blocking reader
 ///
 /// Typically you'd need that to check for output of a process without blocking your thread.
 /// Internally a thread is spawned and the output is read ahead so when
-/// calling `read_line` or `read_until` it reads from an internal buffer
+/// calling `read_line` or `read_until` it reads from an internal buffer.
+/// Alternatively the reader polls a file descriptor in the calling thread, see [`Self::from_fd`].
 pub struct NBReader {
-    reader: Receiver<Result<PipedChar, PipeError>>,
+    source: Source,
     buffer: String,
     eof: bool,
     timeout: Option<time::Duration>,
 }
 
+enum Source {
+    /// A background thread reads ahead and sends what it read
+    Thread {
+        // declared first, so that the channel is closed before the thread is joined
+        rx: Receiver<Result<PipedChar, PipeError>>,
+        /// Readable whenever the thread sent something, or after it ended
+        notified: File,
+        _thread: ReaderThread,
+    },
+    /// Read whatever is available when asked for
+    Poll {
+        file: File,
+        filter: EscapeCodeFilter,
+        log: Log,
+    },
+}
+
 impl NBReader {
     /// Create a new reader instance
     ///
+    /// The background thread ends at EOF or on a read error. When the reader is dropped
+    /// before, the thread can't be woken up if it waits for `f`, it ends with its next read.
+    /// Readers of a file descriptor don't have this problem, see [`Self::with_fd`].
+    ///
     /// # Arguments
     ///
     /// - `f`: file like object
     /// - `options`: see [`Options`]
     pub fn new<R: Read + Send + 'static>(f: R, options: Options) -> NBReader {
         let (tx, rx) = channel();
+        let timeout = options.timeout_ms.map(time::Duration::from_millis);
+        let (notified, notify) = notify_pipe().expect("cannot create pipe");
+        let handle = thread::spawn(move || read_ahead(f, |_| true, &tx, &notify, options));
+        NBReader::with_thread(rx, notified, handle, None, timeout)
+    }
 
-        // spawn a thread which reads one char and sends it to tx
-        thread::spawn(move || -> Result<(), Error> {
-            let mut reader = BufReader::new(f);
-            let mut byte = [0u8];
-            let mut in_escape_code = false;
-
-            loop {
-                match reader.read(&mut byte) {
-                    Ok(0) => {
-                        tx.send(Ok(PipedChar::EOF))
-                            .map_err(|_| Error::MpscSendError)?;
-                        break;
-                    }
-                    Ok(_) => {
-                        if options.strip_ansi_escape_codes && byte[0] == 27 {
-                            in_escape_code = true;
-                        } else if options.strip_ansi_escape_codes && in_escape_code {
-                            if char::from(byte[0]).is_alphabetic() {
-                                in_escape_code = false;
-                            }
-                        } else {
-                            tx.send(Ok(PipedChar::Char(byte[0])))
-                                .map_err(|_| Error::MpscSendError)?;
-                        }
-                    }
-                    Err(error) => {
-                        tx.send(Err(PipeError::IO(error)))
-                            .map_err(|_| Error::MpscSendError)?;
+    /// Create a new reader instance for a file descriptor, like [`Self::new`]
+    ///
+    /// The background thread also waits until the reader is dropped,
+    /// which wakes it up and waits until it ended.
+    pub fn with_fd(fd: OwnedFd, options: Options) -> Result<NBReader, Error> {
+        let (tx, rx) = channel();
+        let timeout = options.timeout_ms.map(time::Duration::from_millis);
+        let (wake, waker) = cloexec_pipe()?;
+        let (notified, notify) = notify_pipe()?;
+        let file = File::from(fd);
+        let handle = thread::spawn(move || {
+            // the wake pipe is closed when the reader is dropped
+            let ready = |file: &File| {
+                let mut fds = [
+                    PollFd::new(file.as_fd(), PollFlags::POLLIN),
+                    PollFd::new(wake.as_fd(), PollFlags::POLLIN),
+                ];
+                loop {
+                    match poll(&mut fds, PollTimeout::NONE) {
+                        Ok(_) => break,
+                        Err(Errno::EINTR) => continue,
+                        Err(_) => return false,
                     }
                 }
-            }
-            Ok(())
-            // don't do error handling as on an error it was most probably
-            // the main thread which exited (remote hangup)
+                fds[1].revents().is_none_or(|revents| revents.is_empty())
+            };
+            read_ahead(file, ready, &tx, &notify, options);
         });
+        Ok(NBReader::with_thread(
+            rx,
+            notified,
+            handle,
+            Some(waker),
+            timeout,
+        ))
+    }
+
+    fn with_thread(
+        rx: Receiver<Result<PipedChar, PipeError>>,
+        notified: File,
+        handle: thread::JoinHandle<()>,
+        waker: Option<OwnedFd>,
+        timeout: Option<time::Duration>,
+    ) -> NBReader {
         // allocate string with a initial capacity of 1024, so when appending chars
         // we don't need to reallocate memory often
         NBReader {
-            reader: rx,
+            source: Source::Thread {
+                rx,
+                notified,
+                _thread: ReaderThread {
+                    handle: Some(handle),
+                    waker,
+                },
+            },
+            buffer: String::with_capacity(1024),
+            eof: false,
+            timeout,
+        }
+    }
+
+    /// Create a reader which doesn't spawn a thread but reads from `fd` in the calling thread
+    ///
+    /// Output is only read while waiting for it, e.g. in [`Self::read_until`], and `fd`
+    /// is polled in between. Otherwise this behaves like [`Self::new`].
+    pub fn from_fd(fd: OwnedFd, mut options: Options) -> NBReader {
+        NBReader {
+            source: Source::Poll {
+                file: fd.into(),
+                filter: EscapeCodeFilter::new(options.strip_ansi_escape_codes),
+                log: options.take_read_log(),
+            },
             buffer: String::with_capacity(1024),
             eof: false,
             timeout: options.timeout_ms.map(time::Duration::from_millis),
         }
     }
 
-    /// Reads all available chars from the read channel and stores them in [`Self::buffer`]
+    /// Reads all available chars and stores them in [`Self::buffer`]
     fn read_into_buffer(&mut self) -> Result<(), Error> {
         if self.eof {
             return Ok(());
         }
-        while let Ok(from_channel) = self.reader.try_recv() {
-            match from_channel {
-                Ok(PipedChar::Char(c)) => self.buffer.push(c as char),
-                Ok(PipedChar::EOF) => self.eof = true,
-                // this is just from experience, e.g. "sleep 5" returns the other error which
-                // most probably means that there is no stdout stream at all -> send EOF
-                // this only happens on Linux, not on OSX
-                Err(PipeError::IO(ref err)) => {
+        let rx = match &mut self.source {
+            Source::Thread { rx, notified, .. } => {
+                // drain the notifications first, so that none for what's received below is missed
+                let mut drained = [0; 64];
+                while matches!(notified.read(&mut drained), Ok(n) if n > 0) {}
+                rx
+            }
+            Source::Poll { file, filter, log } => {
+                let mut chunk = [0; 1024];
+                while !self.eof && readable(file, PollTimeout::ZERO)? {
+                    match file.read(&mut chunk) {
+                        Ok(0) => self.eof = true,
+                        Ok(n) => {
+                            log.log(&chunk[..n]);
+                            for &byte in &chunk[..n] {
+                                if filter.keep(byte) {
+                                    self.buffer.push(byte as char);
+                                }
+                            }
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                        // the caller may have made the file descriptor non-blocking
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        // a pty returns EIO once all processes using it exited
+                        Err(err) if err.raw_os_error() == Some(5) => self.eof = true,
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                return Ok(());
+            }
+        };
+        loop {
+            match rx.try_recv() {
+                Ok(Ok(PipedChar::Char(c))) => self.buffer.push(c as char),
+                Ok(Ok(PipedChar::EOF)) => self.eof = true,
+                // the thread ended after an error, so there is nothing more to read
+                Ok(Err(PipeError::IO(err))) => {
+                    self.eof = true;
+                    // this is just from experience, e.g. "sleep 5" returns the other error which
+                    // most probably means that there is no stdout stream at all -> send EOF
+                    // this only happens on Linux, not on OSX
                     // For an explanation of why we use `raw_os_error` see:
                     // https://github.com/zhiburt/ptyprocess/commit/df003c8e3ff326f7d17bc723bc7c27c50495bb62
-                    self.eof = err.raw_os_error() == Some(5);
+                    if err.raw_os_error() != Some(5) {
+                        return Err(Error::Io(err));
+                    }
+                }
+                Err(TryRecvError::Empty) => return Ok(()),
+                // the thread panicked
+                Err(TryRecvError::Disconnected) => {
+                    self.eof = true;
+                    return Ok(());
                 }
             }
         }
-        Ok(())
     }
 
     /// Read until needle is found (blocking!)
@@ -163,23 +524,8 @@ impl NBReader {
         let start = time::Instant::now();
 
         loop {
-            self.read_into_buffer()?;
-
-            if let Some(tuple_pos) = find(needle, &self.buffer, self.eof) {
-                let first = self.buffer.drain(..tuple_pos.0).collect();
-                let second = self.buffer.drain(..tuple_pos.1 - tuple_pos.0).collect();
-                return Ok((first, second));
-            }
-
-            // reached end of stream and didn't match -> error
-            // we don't know the reason of eof yet, so we provide an empty string
-            // this will be filled out in session::exp()
-            if self.eof {
-                return Err(Error::EOF {
-                    expected: needle.to_string(),
-                    got: self.buffer.clone(),
-                    exit_code: None,
-                });
+            if let Some(tuple) = self.try_read_until(needle)? {
+                return Ok(tuple);
             }
 
             // ran into timeout
@@ -193,8 +539,77 @@ impl NBReader {
                 }
             }
             // nothing matched: wait a little
-            thread::sleep(time::Duration::from_millis(100));
+            self.wait(time::Duration::from_millis(100))?;
+        }
+    }
+
+    /// Like [`Self::read_until`] but returns `None` instead of waiting if needle isn't found yet
+    pub fn try_read_until(
+        &mut self,
+        needle: &ReadUntil,
+    ) -> Result<Option<(String, String)>, Error> {
+        self.read_into_buffer()?;
+        take_match(&mut self.buffer, needle, self.eof)
+    }
+
+    /// Wait at most `duration` for more output to arrive
+    pub(crate) fn wait(&self, duration: time::Duration) -> Result<(), Error> {
+        match self.ready_fd() {
+            Some(fd) => {
+                let timeout = PollTimeout::try_from(duration).unwrap_or(PollTimeout::MAX);
+                let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+                match poll(&mut fds, timeout) {
+                    Ok(_) | Err(Errno::EINTR) => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            // nothing more arrives, but the caller may wait for something else
+            None => thread::sleep(duration),
+        }
+        Ok(())
+    }
+
+    /// The file descriptor which becomes readable when more output may have arrived,
+    /// or `None` once the end of the stream was read
+    pub(crate) fn ready_fd(&self) -> Option<BorrowedFd<'_>> {
+        if self.eof {
+            return None;
         }
+        match &self.source {
+            Source::Thread { notified, .. } => Some(notified.as_fd()),
+            Source::Poll { file, .. } => Some(file.as_fd()),
+        }
+    }
+
+    /// Whether the end of the stream was reached (non-blocking).
+    ///
+    /// For a pty this means that every process holding the slave side closed it,
+    /// which is not necessarily the same as the spawned process having exited.
+    pub fn is_eof(&mut self) -> bool {
+        // discard eventual errors, EOF will be handled in read_until correctly
+        let _ = self.read_into_buffer();
+        self.eof
+    }
+
+    /// Take everything which was read so far out of the internal buffer (non-blocking).
+    pub fn take_buffer(&mut self) -> String {
+        let _ = self.read_into_buffer();
+        self.buffer.drain(..).collect()
+    }
+
+    /// Like [`Self::take_buffer`] but returns the bytes as they were read
+    pub(crate) fn take_bytes(&mut self) -> Vec<u8> {
+        let _ = self.read_into_buffer();
+        // every byte is stored as the char with the same value
+        self.buffer.drain(..).map(|c| c as u8).collect()
+    }
+
+    pub(crate) fn timeout(&self) -> Option<time::Duration> {
+        self.timeout
+    }
+
+    pub(crate) fn buffer(&self) -> &str {
+        &self.buffer
     }
 
     /// Try to read one char from internal buffer (non-blocking).
@@ -211,6 +626,222 @@ impl NBReader {
     }
 }
 
+/// The background thread of [`NBReader`], which is joined when dropped
+struct ReaderThread {
+    handle: Option<thread::JoinHandle<()>>,
+    /// Closing this wakes the thread up if it waits for output
+    waker: Option<OwnedFd>,
+}
+
+impl Drop for ReaderThread {
+    fn drop(&mut self) {
+        let wakeable = self.waker.take().is_some();
+        if let Some(handle) = self.handle.take() {
+            // don't block if the thread can't be woken up, it ends with its next read
+            if wakeable || handle.is_finished() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+/// Read chunks and send them char by char to `tx`, until EOF, an error,
+/// the receiver is gone or `ready` returns false
+fn read_ahead<R: Read>(
+    reader: R,
+    mut ready: impl FnMut(&R) -> bool,
+    tx: &Sender<Result<PipedChar, PipeError>>,
+    notify: &OwnedFd,
+    mut options: Options,
+) {
+    // a full pipe is readable anyway
+    let notify = || {
+        let _ = nix::unistd::write(notify, &[0]);
+    };
+    let mut reader = BufReader::new(reader);
+    let mut filter = EscapeCodeFilter::new(options.strip_ansi_escape_codes);
+    let mut log = options.take_read_log();
+
+    while ready(reader.get_ref()) {
+        match reader.fill_buf() {
+            Ok([]) => {
+                let _ = tx.send(Ok(PipedChar::EOF));
+                notify();
+                break;
+            }
+            Ok(chunk) => {
+                log.log(chunk);
+                for &byte in chunk {
+                    if filter.keep(byte) && tx.send(Ok(PipedChar::Char(byte))).is_err() {
+                        return;
+                    }
+                }
+                notify();
+                let n = chunk.len();
+                reader.consume(n);
+            }
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            // a non-blocking reader without a way to wait for it, try again a bit later
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(10));
+            }
+            Err(error) => {
+                let _ = tx.send(Err(PipeError::IO(error)));
+                notify();
+                break;
+            }
+        }
+    }
+}
+
+/// A non-blocking pipe, which the reader thread writes to after sending output
+fn notify_pipe() -> Result<(File, OwnedFd), Error> {
+    let (notified, notify) = cloexec_pipe()?;
+    for fd in [&notified, &notify] {
+        let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+        fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    }
+    Ok((notified.into(), notify))
+}
+
+/// Whether `file` can be read without blocking, waits at most `timeout`
+fn readable(file: &File, timeout: PollTimeout) -> Result<bool, Error> {
+    let mut fds = [PollFd::new(file.as_fd(), PollFlags::POLLIN)];
+    match poll(&mut fds, timeout) {
+        Ok(n) => Ok(n > 0),
+        Err(Errno::EINTR) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Runs the matchers of [`NBReader`] on output which is fed in by the caller
+///
+/// This doesn't read anything by itself, which allows to drive the matching from an
+/// own event loop: register the process (e.g. [`crate::process::PtyProcess`], which
+/// implements `AsFd`) for readiness events, read whatever is available and [`Self::feed`] it.
+///
+/// # Example
+///
+/// ```
+/// use rexpect::reader::{Matcher, Options, ReadUntil};
+///
+/// let mut matcher = Matcher::new(Options::default());
+/// let needle = ReadUntil::String("$ ".to_owned());
+/// matcher.feed(b"Welcome\r\n$");
+/// assert_eq!(None, matcher.try_match(&needle).unwrap());
+/// matcher.feed(b" ");
+/// assert_eq!(
+///     Some(("Welcome\r\n".to_owned(), "$ ".to_owned())),
+///     matcher.try_match(&needle).unwrap()
+/// );
+/// matcher.feed_eof();
+/// assert!(matcher.try_match(&needle).is_err());
+/// ```
+pub struct Matcher {
+    buffer: String,
+    eof: bool,
+    filter: EscapeCodeFilter,
+    log: Log,
+}
+
+impl Matcher {
+    /// Only [`Options::strip_ansi_escape_codes`] and the read logs of `options` are used
+    pub fn new(mut options: Options) -> Self {
+        Self {
+            buffer: String::with_capacity(1024),
+            eof: false,
+            filter: EscapeCodeFilter::new(options.strip_ansi_escape_codes),
+            log: options.take_read_log(),
+        }
+    }
+
+    /// Append output of the process
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.log.log(bytes);
+        for &byte in bytes {
+            if self.filter.keep(byte) {
+                self.buffer.push(byte as char);
+            }
+        }
+    }
+
+    /// Mark the end of the output, needed to match [`ReadUntil::EOF`]
+    pub fn feed_eof(&mut self) {
+        self.eof = true;
+    }
+
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// The output which wasn't matched yet
+    pub fn buffer(&self) -> &str {
+        &self.buffer
+    }
+
+    /// Like [`NBReader::try_read_until`], on a match the output up to the end of the match
+    /// is removed from the buffer
+    ///
+    /// Returns [`Error::EOF`] if the end of the output was fed and `needle` doesn't match.
+    pub fn try_match(&mut self, needle: &ReadUntil) -> Result<Option<(String, String)>, Error> {
+        take_match(&mut self.buffer, needle, self.eof)
+    }
+}
+
+/// Remove the output up to and including the match of `needle` from `buffer`
+pub(crate) fn take_match(
+    buffer: &mut String,
+    needle: &ReadUntil,
+    eof: bool,
+) -> Result<Option<(String, String)>, Error> {
+    if let Some(tuple_pos) = find(needle, buffer, eof) {
+        let first = buffer.drain(..tuple_pos.0).collect();
+        let second = buffer.drain(..tuple_pos.1 - tuple_pos.0).collect();
+        return Ok(Some((first, second)));
+    }
+
+    // reached end of stream and didn't match -> error
+    // we don't know the reason of eof yet, so we don't provide an exit report
+    // this will be filled out by session::PtySession
+    if eof {
+        return Err(Error::EOF {
+            expected: needle.to_string(),
+            got: buffer.clone(),
+            exit_report: None,
+        });
+    }
+    Ok(None)
+}
+
+/// Filters escape codes out of the output, see [`Options::strip_ansi_escape_codes`]
+pub(crate) struct EscapeCodeFilter {
+    enabled: bool,
+    in_escape_code: bool,
+}
+
+impl EscapeCodeFilter {
+    pub(crate) fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            in_escape_code: false,
+        }
+    }
+
+    /// Whether `byte` is kept, i.e. not part of an escape code
+    pub(crate) fn keep(&mut self, byte: u8) -> bool {
+        if self.enabled && byte == 27 {
+            self.in_escape_code = true;
+        } else if self.enabled && self.in_escape_code {
+            if char::from(byte).is_alphabetic() {
+                self.in_escape_code = false;
+            }
+        } else {
+            return true;
+        }
+        false
+    }
+}
+
 /// See [`NBReader::read_until`]
 ///
 /// Note that when used with a tty the lines end with \r\n
@@ -269,7 +900,7 @@ impl fmt::Display for ReadUntil {
 /// Tuple with match positions:
 /// 1. position before match (0 in case of EOF and Nbytes)
 /// 2. position after match
-fn find(needle: &ReadUntil, buffer: &str, eof: bool) -> Option<(usize, usize)> {
+pub(crate) fn find(needle: &ReadUntil, buffer: &str, eof: bool) -> Option<(usize, usize)> {
     match needle {
         ReadUntil::String(s) => buffer.find(s).map(|pos| (pos, pos + s.len())),
         ReadUntil::Regex(pattern) => pattern.find(buffer).map(|mat| (mat.start(), mat.end())),
@@ -457,4 +1088,28 @@ mod tests {
         assert_eq!(None, r.try_read());
         assert_eq!(None, r.try_read());
     }
+
+    #[test]
+    fn test_read_error() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
+        }
+        let mut r = NBReader::new(Failing, Options::new().timeout_ms(Some(1000)));
+        assert!(matches!(r.read_until(&ReadUntil::EOF), Err(Error::Io(_))));
+        assert!(r.is_eof());
+    }
+
+    #[test]
+    fn test_drop_joins_thread() -> Result<(), Error> {
+        let (reader, mut writer) = cloexec_pipe()?;
+        let mut r = NBReader::with_fd(reader, Options::new().timeout_ms(Some(1000)))?;
+        nix::unistd::write(&mut writer, b"hello")?;
+        r.read_until(&ReadUntil::String("hello".to_owned()))?;
+        // the writer is still open, the thread must be woken up to be joined
+        drop(r);
+        Ok(())
+    }
 }
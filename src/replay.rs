@@ -0,0 +1,385 @@
+//! Replay a recorded session as a fake process, e.g. to test automation without the real program
+
+use crate::error::Error;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::{thread, time};
+
+/// A session recorded with [`Options::record_asciicast`](crate::reader::Options::record_asciicast),
+/// which acts as the process
+///
+/// The recorded output is replayed in order, with the recorded delays in between.
+/// When the process received input in the recording, the replay waits until the same input
+/// is sent, any other input is an error.
+///
+/// # Example
+///
+/// ```
+/// use rexpect::replay::Replay;
+/// use rexpect::spawn_stream;
+/// use std::time::Duration;
+/// # use rexpect::error::Error;
+///
+/// # fn main() -> Result<(), Error> {
+/// let recording = r#"{"version": 2, "width": 80, "height": 24}
+/// [0.1, "o", "name? "]
+/// [1.5, "i", "polly\n"]
+/// [1.6, "o", "hello, polly!\r\n"]
+/// "#;
+/// // don't wait longer than 100 ms between two events
+/// let replay = Replay::from_asciicast(recording.as_bytes())?.max_delay(Duration::from_millis(100));
+/// let (reader, writer) = replay.into_parts();
+/// let mut s = spawn_stream(reader, writer, Some(1000));
+/// s.exp_string("name? ")?;
+/// s.send_line("polly")?;
+/// assert_eq!("hello, polly!", s.read_line()?);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Replay {
+    events: VecDeque<Event>,
+    input: Vec<u8>,
+    max_delay: Option<time::Duration>,
+}
+
+enum Event {
+    Output {
+        time: f64,
+        data: Vec<u8>,
+    },
+    /// Waits until the recorded input up to `end` was sent
+    Input {
+        time: f64,
+        end: usize,
+    },
+}
+
+impl Replay {
+    /// Read a recording in asciicast v2 format
+    ///
+    /// Only output (`"o"`) and input (`"i"`) events are replayed, other events are ignored.
+    pub fn from_asciicast(reader: impl BufRead) -> Result<Self, Error> {
+        let mut events = VecDeque::new();
+        let mut input = Vec::new();
+        let mut header = false;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason: &str| Error::InvalidRecording {
+                line: i + 1,
+                reason: reason.to_owned(),
+            };
+            if !header {
+                if !line.starts_with('{') {
+                    return Err(invalid("expected the asciicast header"));
+                }
+                header = true;
+                continue;
+            }
+            let (time, code, data) = parse_event(line).ok_or_else(|| invalid("invalid event"))?;
+            match code.as_str() {
+                "o" => events.push_back(Event::Output {
+                    time,
+                    data: data.into_bytes(),
+                }),
+                "i" => {
+                    input.extend_from_slice(data.as_bytes());
+                    events.push_back(Event::Input {
+                        time,
+                        end: input.len(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        if !header {
+            return Err(Error::InvalidRecording {
+                line: 0,
+                reason: "the recording is empty".to_owned(),
+            });
+        }
+        Ok(Self {
+            events,
+            input,
+            max_delay: None,
+        })
+    }
+
+    /// Limit the delays between two events, `Some(Duration::ZERO)` replays without any delays
+    ///
+    /// `None` (the default) keeps the recorded timing.
+    pub fn max_delay(mut self, max_delay: impl Into<Option<time::Duration>>) -> Self {
+        self.max_delay = max_delay.into();
+        self
+    }
+
+    /// Reader and writer of the fake process, to be passed to
+    /// [`StreamSession::new`](crate::session::StreamSession::new) or [`crate::spawn_stream`]
+    pub fn into_parts(self) -> (ReplayReader, ReplayWriter) {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                input: self.input,
+                sent: 0,
+                closed: false,
+            }),
+            sent: Condvar::new(),
+        });
+        let reader = ReplayReader {
+            events: self.events,
+            pending: Vec::new(),
+            max_delay: self.max_delay,
+            last: (0.0, time::Instant::now()),
+            shared: shared.clone(),
+        };
+        (reader, ReplayWriter { shared })
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when input was sent or the writer was closed
+    sent: Condvar,
+}
+
+struct State {
+    /// All recorded input, concatenated
+    input: Vec<u8>,
+    /// How much of `input` was sent so far
+    sent: usize,
+    /// Set when unexpected input was sent or the writer was dropped
+    closed: bool,
+}
+
+/// Output of the replayed process, see [`Replay::into_parts`]
+///
+/// Reaches EOF at the end of the recording or when unexpected input was sent.
+pub struct ReplayReader {
+    events: VecDeque<Event>,
+    /// Rest of the output event which is currently read
+    pending: Vec<u8>,
+    max_delay: Option<time::Duration>,
+    /// Recorded time of the last event and when it was replayed
+    last: (f64, time::Instant),
+    shared: Arc<Shared>,
+}
+
+impl ReplayReader {
+    fn wait_until(&mut self, time: f64) {
+        let (last_time, last_instant) = self.last;
+        let mut delay = time::Duration::from_secs_f64((time - last_time).max(0.0));
+        if let Some(max_delay) = self.max_delay {
+            delay = delay.min(max_delay);
+        }
+        if let Some(remaining) = delay.checked_sub(last_instant.elapsed()) {
+            thread::sleep(remaining);
+        }
+        self.last = (time, time::Instant::now());
+    }
+}
+
+impl Read for ReplayReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            match self.events.pop_front() {
+                None => return Ok(0),
+                Some(Event::Output { time, data }) => {
+                    self.wait_until(time);
+                    self.pending = data;
+                }
+                Some(Event::Input { time, end }) => {
+                    let state = self.shared.state.lock().map_err(|_| poisoned())?;
+                    let state = self
+                        .shared
+                        .sent
+                        .wait_while(state, |state| state.sent < end && !state.closed)
+                        .map_err(|_| poisoned())?;
+                    if state.sent < end {
+                        return Ok(0);
+                    }
+                    drop(state);
+                    // the recorded delays are relative to the input, not to when we started waiting
+                    self.last = (time, time::Instant::now());
+                }
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+/// Input of the replayed process, see [`Replay::into_parts`]
+///
+/// Writing anything else than the recorded input fails with [`io::ErrorKind::InvalidInput`].
+pub struct ReplayWriter {
+    shared: Arc<Shared>,
+}
+
+impl Write for ReplayWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.shared.state.lock().map_err(|_| poisoned())?;
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let expected = &state.input[state.sent..];
+        if !expected.starts_with(buf) {
+            let expected = &expected[..expected.len().min(buf.len())];
+            let error = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "replay expected input {:?} but got {:?}",
+                    String::from_utf8_lossy(expected),
+                    String::from_utf8_lossy(buf)
+                ),
+            );
+            state.closed = true;
+            self.shared.sent.notify_all();
+            return Err(error);
+        }
+        state.sent += buf.len();
+        self.shared.sent.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ReplayWriter {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.closed = true;
+        }
+        self.shared.sent.notify_all();
+    }
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("replay state is poisoned")
+}
+
+/// Parse an event line `[time, "code", "data"]`
+fn parse_event(line: &str) -> Option<(f64, String, String)> {
+    let rest = line.strip_prefix('[')?.strip_suffix(']')?;
+    let (time, rest) = rest.split_once(',')?;
+    let time = time.trim().parse().ok()?;
+    let (code, rest) = parse_string(rest.trim_start())?;
+    let rest = rest.trim_start().strip_prefix(',')?;
+    let (data, rest) = parse_string(rest.trim_start())?;
+    rest.trim().is_empty().then_some((time, code, data))
+}
+
+/// Parse a JSON string at the start of `s`, returns it and the rest of `s`
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut string = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                '"' => string.push('"'),
+                '\\' => string.push('\\'),
+                '/' => string.push('/'),
+                'b' => string.push('\x08'),
+                'f' => string.push('\x0c'),
+                'n' => string.push('\n'),
+                'r' => string.push('\r'),
+                't' => string.push('\t'),
+                'u' => {
+                    let high = parse_hex(&mut chars)?;
+                    let c = if (0xd800..0xdc00).contains(&high) {
+                        // surrogate pair
+                        if chars.next()?.1 != '\\' || chars.next()?.1 != 'u' {
+                            return None;
+                        }
+                        let low = parse_hex(&mut chars)?;
+                        char::from_u32(
+                            0x10000 + ((high - 0xd800) << 10) + (low.checked_sub(0xdc00)?),
+                        )
+                    } else {
+                        char::from_u32(high)
+                    };
+                    string.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                _ => return None,
+            },
+            c => string.push(c),
+        }
+    }
+    None
+}
+
+fn parse_hex(chars: &mut impl Iterator<Item = (usize, char)>) -> Option<u32> {
+    let digits: String = chars.take(4).map(|(_, c)| c).collect();
+    u32::from_str_radix(&digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::Options;
+    use crate::session::{StreamSession, spawn_with_options};
+    use std::process::Command;
+
+    #[test]
+    fn test_parse_event() {
+        assert_eq!(
+            Some((1.25, "o".to_owned(), "a\"\r\n\u{1b}[0m\u{1f600}".to_owned())),
+            parse_event(r#"[1.25, "o", "a\"\r\n\u001b[0m😀"]"#)
+        );
+        assert_eq!(None, parse_event(r#"[1.25, "o", "unterminated]"#));
+    }
+
+    #[test]
+    fn test_replay() -> Result<(), Error> {
+        let recording = r##"{"version": 2, "width": 80, "height": 24}
+[0.5, "o", "login: "]
+[1.0, "i", "root\n"]
+[10.0, "o", "# "]
+[10.5, "r", "100x50"]
+"##;
+        let replay = Replay::from_asciicast(recording.as_bytes())?.max_delay(time::Duration::ZERO);
+        let (reader, writer) = replay.into_parts();
+        let mut s = StreamSession::new(reader, writer, Options::new().timeout_ms(Some(1000)));
+        s.exp_string("login: ")?;
+        s.send_line("root")?;
+        s.exp_string("# ")?;
+        s.exp_eof()?;
+
+        // unexpected input
+        let replay = Replay::from_asciicast(recording.as_bytes())?.max_delay(time::Duration::ZERO);
+        let (reader, writer) = replay.into_parts();
+        let mut s = StreamSession::new(reader, writer, Options::new().timeout_ms(Some(1000)));
+        s.exp_string("login: ")?;
+        match s.send_line("admin") {
+            Err(Error::Io(err)) => assert_eq!(io::ErrorKind::InvalidInput, err.kind()),
+            result => panic!("expected an input mismatch, got {result:?}"),
+        }
+        s.exp_eof()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_recording() -> Result<(), Error> {
+        let cast = tempfile::NamedTempFile::new()?;
+        let options = Options::new()
+            .timeout_ms(Some(1000))
+            .record_asciicast(cast.reopen()?);
+        let mut p = spawn_with_options(Command::new("cat"), options)?;
+        p.send_line("hello")?;
+        assert_eq!("hello", p.read_line()?);
+
+        let replay = Replay::from_asciicast(io::BufReader::new(cast.reopen()?))?;
+        let (reader, writer) = replay.into_parts();
+        let mut s = StreamSession::new(reader, writer, Options::new().timeout_ms(Some(1000)));
+        s.send_line("hello")?;
+        assert_eq!("hello", s.read_line()?);
+        Ok(())
+    }
+}
@@ -1,36 +1,203 @@
 //! Main module of rexpect: start new process and interact with it
 
+use crate::asciicast::RECORDED_ENV;
 use crate::error::Error; // load error-chain
-use crate::process::PtyProcess;
+use crate::keys::{self, Key, Mouse};
+use crate::log::LogWriter;
+use crate::process::{self, ExitReport, PipeProcess, PtyProcess, SpawnOptions, Termios};
 use crate::reader::{NBReader, Regex};
 pub use crate::reader::{Options, ReadUntil};
+use crate::terminal::{ModeTracker, Modes, MouseTracking};
+use crate::writer::{Output, Typing};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sys::termios;
+use std::convert::Infallible;
 use std::fs::File;
-use std::io::LineWriter;
 use std::io::prelude::*;
+use std::io::{IsTerminal, LineWriter};
 use std::ops::{Deref, DerefMut};
-use std::process::Command;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::process::{ChildStdin, Command};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
 use tempfile;
 
 pub struct StreamSession<W: Write> {
-    writer: LineWriter<W>,
-    reader: NBReader,
+    writer: StreamWriter<W>,
+    reader: StreamReader,
+}
+
+// make StreamReader's methods available directly
+impl<W: Write> Deref for StreamSession<W> {
+    type Target = StreamReader;
+    fn deref(&self) -> &StreamReader {
+        &self.reader
+    }
+}
+
+impl<W: Write> DerefMut for StreamSession<W> {
+    fn deref_mut(&mut self) -> &mut StreamReader {
+        &mut self.reader
+    }
 }
 
 impl<W: Write> StreamSession<W> {
     pub fn new<R: Read + Send + 'static>(reader: R, writer: W, options: Options) -> Self {
-        Self {
-            writer: LineWriter::new(writer),
-            reader: NBReader::new(reader, options),
-        }
+        let Ok(session) = Self::with_reader(Output::Direct(writer), options, |options| {
+            Ok::<_, Infallible>(StreamReader::new(reader, options))
+        });
+        session
+    }
+
+    /// Like [`Self::new`], but the reader polls `reader` if [`Options::poll_reader`] is set
+    /// and `writer` is written to according to [`Options::write_timeout_ms`] and
+    /// [`Options::writer_thread`]
+    pub(crate) fn from_file(reader: File, writer: W, options: Options) -> Result<Self, Error>
+    where
+        W: Into<OwnedFd>,
+    {
+        let writer = Output::from_fd(writer, &options)?;
+        Self::with_reader(writer, options, |options| {
+            StreamReader::from_file(reader, options)
+        })
+    }
+
+    fn with_reader<E>(
+        writer: Output<W>,
+        mut options: Options,
+        reader: impl FnOnce(Options) -> Result<StreamReader, E>,
+    ) -> Result<Self, E> {
+        options.start_recording(None);
+        let log = options.take_send_log();
+        Ok(Self {
+            writer: StreamWriter {
+                writer: LineWriter::new(LogWriter::new(writer, log)),
+                delay_before_send: options
+                    .delay_before_send_ms
+                    .map(time::Duration::from_millis),
+                typing: options
+                    .typing_delay_ms
+                    .map(|(delay_ms, jitter_ms)| Typing::new(delay_ms, jitter_ms)),
+                term: options.term.clone().unwrap_or_else(|| "xterm".to_owned()),
+            },
+            reader: reader(options)?,
+        })
+    }
+
+    /// Split into the reading and the writing side, which can be used from different threads
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rexpect::session::StreamSession;
+    /// use rexpect::reader::Options;
+    /// use rexpect::mock::duplex;
+    /// use std::io::{BufRead, BufReader, Write};
+    /// use std::thread;
+    /// # use rexpect::error::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let (session, process) = duplex();
+    /// let (reader, writer) = session.split();
+    /// let session = StreamSession::new(reader, writer, Options::new().timeout_ms(Some(1000)));
+    /// // a process which echoes every line
+    /// thread::spawn(move || {
+    ///     let (reader, mut writer) = process.split();
+    ///     for line in BufReader::new(reader).lines() {
+    ///         writeln!(writer, "{}", line?)?;
+    ///     }
+    ///     Ok::<_, std::io::Error>(())
+    /// });
+    ///
+    /// let (mut reader, mut writer) = session.split();
+    /// let producer = thread::spawn(move || {
+    ///     for i in 0..3 {
+    ///         writer.send_line(&i.to_string())?;
+    ///     }
+    ///     Ok::<_, Error>(writer)
+    /// });
+    /// for i in 0..3 {
+    ///     assert_eq!(i.to_string(), reader.read_line()?);
+    /// }
+    /// let writer = producer.join().unwrap()?;
+    /// let mut session = StreamSession::reunite(reader, writer);
+    /// session.send_line("done")?;
+    /// assert_eq!("done", session.read_line()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn split(self) -> (StreamReader, StreamWriter<W>) {
+        (self.reader, self.writer)
+    }
+
+    /// Put the halves of [`Self::split`] back together
+    pub fn reunite(reader: StreamReader, writer: StreamWriter<W>) -> Self {
+        Self { writer, reader }
+    }
+
+    /// See [`StreamWriter::send_line`]
+    pub fn send_line(&mut self, line: &str) -> Result<usize, Error> {
+        self.writer.send_line(line)
     }
 
+    /// See [`StreamWriter::send`]
+    pub fn send(&mut self, s: &str) -> Result<usize, Error> {
+        self.writer.send(s)
+    }
+
+    /// See [`StreamWriter::send_control`]
+    pub fn send_control(&mut self, c: char) -> Result<(), Error> {
+        self.writer.send_control(c)
+    }
+
+    /// See [`StreamWriter::send_keys`]
+    pub fn send_keys(&mut self, keys: &str) -> Result<(), Error> {
+        self.writer.send_keys(keys)
+    }
+
+    /// See [`StreamWriter::send_key`]
+    pub fn send_key(&mut self, key: Key) -> Result<(), Error> {
+        self.writer.send_key(key)
+    }
+
+    /// See [`StreamWriter::flush`]
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+
+    /// See [`StreamWriter::set_delay_before_send_ms`]
+    pub fn set_delay_before_send_ms(&mut self, delay_ms: Option<u64>) {
+        self.writer.set_delay_before_send_ms(delay_ms);
+    }
+
+    /// See [`StreamWriter::set_typing_delay_ms`]
+    pub fn set_typing_delay_ms(&mut self, delay_ms: Option<u64>, jitter_ms: u64) {
+        self.writer.set_typing_delay_ms(delay_ms, jitter_ms);
+    }
+}
+
+/// The writing side of a session, see [`StreamSession::split`]
+pub struct StreamWriter<W: Write> {
+    writer: LineWriter<LogWriter<Output<W>>>,
+    delay_before_send: Option<time::Duration>,
+    typing: Option<Typing>,
+    /// See [`Options::term`]
+    term: String,
+}
+
+impl<W: Write> StreamWriter<W> {
     /// Sends string and a newline to process
     ///
     /// This is guaranteed to be flushed to the process
     /// returns number of written bytes
+    ///
+    /// A pty in canonical mode drops what doesn't fit into a line, this doesn't check for it.
+    /// See [`PtySession::send_line_with_options`], which isn't available after
+    /// [`PtySession::split`].
     pub fn send_line(&mut self, line: &str) -> Result<usize, Error> {
         let mut len = self.send(line)?;
-        len += self.writer.write(b"\n")?;
+        len += self.write_input("\n")?;
         Ok(len)
     }
 
@@ -41,7 +208,49 @@ impl<W: Write> StreamSession<W> {
     ///
     /// Returns number of written bytes
     pub fn send(&mut self, s: &str) -> Result<usize, Error> {
-        self.writer.write(s.as_bytes()).map_err(Error::from)
+        self.delay_before_send();
+        self.write_input(s).map_err(Error::from)
+    }
+
+    /// See [`Options::delay_before_send_ms`]
+    pub fn set_delay_before_send_ms(&mut self, delay_ms: Option<u64>) {
+        self.delay_before_send = delay_ms.map(time::Duration::from_millis);
+    }
+
+    /// See [`Options::typing_delay_ms`]
+    pub fn set_typing_delay_ms(&mut self, delay_ms: Option<u64>, jitter_ms: u64) {
+        self.typing = delay_ms.map(|delay_ms| Typing::new(delay_ms, jitter_ms));
+    }
+
+    fn delay_before_send(&self) {
+        if let Some(delay) = self.delay_before_send {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Write `input` at once, or one character at a time when typing
+    fn write_input(&mut self, input: &str) -> std::io::Result<usize> {
+        let Some(typing) = self.typing else {
+            self.writer.write_all(input.as_bytes())?;
+            return Ok(input.len());
+        };
+        let mut buf = [0; 4];
+        for c in input.chars() {
+            self.writer.write_all(c.encode_utf8(&mut buf).as_bytes())?;
+            self.writer.flush()?;
+            typing.pause();
+        }
+        Ok(input.len())
+    }
+
+    /// Write a single byte like [`Self::write_input`], e.g. a control character
+    fn write_byte(&mut self, byte: u8) -> std::io::Result<()> {
+        self.writer.write_all(&[byte])?;
+        if let Some(typing) = self.typing {
+            self.writer.flush()?;
+            typing.pause();
+        }
+        Ok(())
     }
 
     /// Send a control code to the running process and consume resulting output line
@@ -51,26 +260,92 @@ impl<W: Write> StreamSession<W> {
     ///
     /// E.g. `send_control('c')` sends ctrl-c.
     pub fn send_control(&mut self, c: char) -> Result<(), Error> {
-        let code = match c {
-            'a'..='z' => c as u8 + 1 - b'a',
-            'A'..='Z' => c as u8 + 1 - b'A',
-            '[' => 27,
-            '\\' => 28,
-            ']' => 29,
-            '^' => 30,
-            '_' => 31,
-            _ => return Err(Error::SendControlError(c)),
-        };
-        self.writer.write_all(&[code])?;
+        let code = keys::control_code(c).ok_or(Error::SendControlError(c))?;
+        self.send_bytes(&[code])
+    }
+
+    /// Send `bytes` right away
+    fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.delay_before_send();
+        self.writer.write_all(bytes)?;
         // stdout is line buffered, so needs a flush
         self.writer.flush()?;
         Ok(())
     }
 
+    /// Send a sequence of keys written in the notation of [`keys::parse`]
+    ///
+    /// The keys are encoded for the terminal type set with [`Options::term`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rexpect::spawn;
+    /// # use rexpect::error::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let mut p = spawn("cat -v", Some(1000))?;
+    /// p.send_keys("a<C-b><Up><Enter>")?;
+    /// p.exp_string("a^B^[[A")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_keys(&mut self, keys: &str) -> Result<(), Error> {
+        let keys = keys::parse(keys)?;
+        self.write_keys(&keys)
+    }
+
+    /// Send a single key, see [`Self::send_keys`]
+    pub fn send_key(&mut self, key: Key) -> Result<(), Error> {
+        self.write_keys(&[key])
+    }
+
+    fn write_keys(&mut self, keys: &[Key]) -> Result<(), Error> {
+        let encoded = keys
+            .iter()
+            .map(|key| key.encode(&self.term))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.delay_before_send();
+        for bytes in encoded {
+            self.writer.write_all(&bytes)?;
+            if let Some(typing) = self.typing {
+                self.writer.flush()?;
+                typing.pause();
+            }
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
     /// Make sure all bytes written via [`Self::send()`] are sent to the process
     pub fn flush(&mut self) -> Result<(), Error> {
         self.writer.flush().map_err(Error::from)
     }
+}
+
+/// The reading side of a session: waits for output of a single stream
+///
+/// Besides being the reading side of [`StreamSession`] this is e.g. used for
+/// stderr of a [`PtySession`], see [`PtySession::stderr`].
+pub struct StreamReader {
+    reader: NBReader,
+}
+
+impl StreamReader {
+    pub fn new<R: Read + Send + 'static>(reader: R, options: Options) -> Self {
+        Self {
+            reader: NBReader::new(reader, options),
+        }
+    }
+
+    /// Like [`Self::new`], but polls `file` if [`Options::poll_reader`] is set
+    pub(crate) fn from_file(file: File, options: Options) -> Result<Self, Error> {
+        let reader = match options.poll_reader {
+            true => NBReader::from_fd(file.into(), options),
+            false => NBReader::with_fd(file.into(), options)?,
+        };
+        Ok(Self { reader })
+    }
 
     /// Read one line (blocking).
     ///
@@ -96,6 +371,13 @@ impl<W: Write> StreamSession<W> {
         self.reader.try_read()
     }
 
+    /// Whether the output stream reached EOF (non-blocking).
+    ///
+    /// See [`NBReader::is_eof`]
+    pub fn is_eof(&mut self) -> bool {
+        self.reader.is_eof()
+    }
+
     /// Wait until we see EOF (i.e. child process has terminated)
     ///
     /// Return all the yet unread output
@@ -154,69 +436,1195 @@ impl<W: Write> StreamSession<W> {
     /// # }
     /// ```
     pub fn exp_any(&mut self, needles: Vec<ReadUntil>) -> Result<(String, String), Error> {
-        self.exp(&ReadUntil::Any(needles))
+        self.exp(&ReadUntil::Any(needles))
+    }
+
+    /// Check whether `needle` matches the output read so far (non-blocking).
+    ///
+    /// Returns `None` instead of waiting if it doesn't match yet, which allows to run
+    /// the matching from an own event loop when the session becomes readable,
+    /// see [`Options::poll_reader`] and the `AsFd` implementation of [`PtySession`].
+    pub fn try_exp(&mut self, needle: &ReadUntil) -> Result<Option<(String, String)>, Error> {
+        self.reader.try_read_until(needle)
+    }
+
+    // wrapper around reader::read_until to give more context for errors
+    fn exp(&mut self, needle: &ReadUntil) -> Result<(String, String), Error> {
+        self.reader.read_until(needle)
+    }
+}
+
+/// Wait for the first of several sessions to match its needle, like `expect -i` of Tcl expect
+///
+/// Sessions are added as [`Expectable`], e.g. a [`PtySession`], [`PipeSession`],
+/// [`StreamSession`] or the stderr of a session.
+///
+/// # Example
+///
+/// ```
+/// use rexpect::session::{MultiExpect, ReadUntil};
+/// use rexpect::spawn;
+/// # use rexpect::error::Error;
+///
+/// # fn main() -> Result<(), Error> {
+/// let mut server = spawn("sh -c 'sleep 0.2; echo listening'", Some(1000))?;
+/// let mut client = spawn("sh -c 'echo connection refused'", Some(1000))?;
+/// let (index, _, matched) = MultiExpect::new(Some(1000))
+///     .add(&mut server, ReadUntil::String("listening".to_owned()))
+///     .add(&mut client, ReadUntil::String("refused".to_owned()))
+///     .exp()?;
+/// assert_eq!((1, "refused".to_owned()), (index, matched));
+/// # Ok(())
+/// # }
+/// ```
+pub struct MultiExpect<'a> {
+    needles: Vec<(Expectable<'a>, ReadUntil)>,
+    timeout: Option<time::Duration>,
+}
+
+/// A session which can be added to [`MultiExpect`]
+///
+/// Created from a mutable reference to the session. For [`PtySession`] and [`PipeSession`]
+/// EOF errors contain the exit report of the child, like those of their own methods.
+pub struct Expectable<'a> {
+    reader: &'a mut StreamReader,
+    exit_report: Option<Box<dyn Fn() -> Option<ExitReport> + 'a>>,
+}
+
+impl<'a> From<&'a mut StreamReader> for Expectable<'a> {
+    fn from(reader: &'a mut StreamReader) -> Self {
+        Self {
+            reader,
+            exit_report: None,
+        }
+    }
+}
+
+impl<'a, W: Write> From<&'a mut StreamSession<W>> for Expectable<'a> {
+    fn from(session: &'a mut StreamSession<W>) -> Self {
+        Self::from(&mut session.reader)
+    }
+}
+
+impl<'a> From<&'a mut PtySession> for Expectable<'a> {
+    fn from(session: &'a mut PtySession) -> Self {
+        let process = &session.process;
+        Self {
+            reader: &mut session.stream.reader,
+            exit_report: Some(Box::new(|| process.exit_report())),
+        }
+    }
+}
+
+impl<'a> From<&'a mut PipeSession> for Expectable<'a> {
+    fn from(session: &'a mut PipeSession) -> Self {
+        let process = &session.process;
+        Self {
+            reader: &mut session.stream.reader,
+            exit_report: Some(Box::new(|| process.exit_report())),
+        }
+    }
+}
+
+impl<'a> MultiExpect<'a> {
+    /// `timeout_ms` is shared by all sessions, see [`spawn`] for its meaning
+    pub fn new(timeout_ms: Option<u64>) -> Self {
+        Self {
+            needles: Vec::new(),
+            timeout: timeout_ms.map(time::Duration::from_millis),
+        }
+    }
+
+    /// Wait for `needle` in the output of `session`
+    ///
+    /// To wait for one of several needles of a session, use [`ReadUntil::Any`].
+    pub fn add(mut self, session: impl Into<Expectable<'a>>, needle: ReadUntil) -> Self {
+        self.needles.push((session.into(), needle));
+        self
+    }
+
+    /// Wait until one of the needles is found (blocking!)
+    ///
+    /// Returns a tuple with:
+    /// 1. the index of the matched session/needle, in the order they were added
+    /// 2. the yet unread output of that session until and without the needle
+    /// 3. the matched needle
+    ///
+    /// A session which reached EOF without matching its needle is skipped. Once all of them
+    /// did, the EOF error of the first one is returned, add [`ReadUntil::EOF`] to a needle
+    /// to handle this.
+    pub fn exp(mut self) -> Result<(usize, String, String), Error> {
+        let start = time::Instant::now();
+        let mut eof_errors: Vec<Option<Error>> = self.needles.iter().map(|_| None).collect();
+        loop {
+            for (i, (session, needle)) in self.needles.iter_mut().enumerate() {
+                if eof_errors[i].is_some() {
+                    continue;
+                }
+                let result = session.reader.reader.try_read_until(needle);
+                let result = match &session.exit_report {
+                    Some(exit_report) => with_exit_report(result, exit_report),
+                    None => result,
+                };
+                match result {
+                    Ok(Some((before, matched))) => return Ok((i, before, matched)),
+                    Ok(None) => {}
+                    Err(err @ Error::EOF { .. }) => eof_errors[i] = Some(err),
+                    Err(err) => return Err(err),
+                }
+            }
+            if eof_errors.iter().all(Option::is_some) {
+                return Err(eof_errors.swap_remove(0).expect("all sessions reached EOF"));
+            }
+
+            let mut remaining = None;
+            if let Some(timeout) = self.timeout {
+                remaining = timeout.checked_sub(start.elapsed());
+                if remaining.is_none() {
+                    let mut expected = Vec::new();
+                    let mut got = Vec::new();
+                    for (i, (session, needle)) in self.needles.iter().enumerate() {
+                        expected.push(format!("{i}: {needle}"));
+                        got.push(format!("{i}: {:?}", session.reader.reader.buffer()));
+                    }
+                    return Err(Error::Timeout {
+                        expected: expected.join(", "),
+                        got: got.join(", "),
+                        timeout,
+                    });
+                }
+            }
+            // wait until one of the sessions which may still match got more output
+            let mut fds: Vec<_> = self
+                .needles
+                .iter()
+                .filter_map(|(session, _)| session.reader.reader.ready_fd())
+                .map(|fd| PollFd::new(fd, PollFlags::POLLIN))
+                .collect();
+            let timeout = remaining
+                .map(|remaining| PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX))
+                .unwrap_or(PollTimeout::NONE);
+            match poll(&mut fds, timeout) {
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+/// Interact with a process with read/write/signals, etc.
+#[allow(dead_code)]
+pub struct PtySession {
+    process: PtyProcess,
+    stream: StreamSession<File>,
+    stderr: Option<StreamReader>,
+    modes: Option<Arc<Mutex<ModeTracker>>>,
+}
+
+// make StreamSession's methods available directly
+impl Deref for PtySession {
+    type Target = StreamSession<File>;
+    fn deref(&self) -> &StreamSession<File> {
+        &self.stream
+    }
+}
+
+impl DerefMut for PtySession {
+    fn deref_mut(&mut self) -> &mut StreamSession<File> {
+        &mut self.stream
+    }
+}
+
+impl PtySession {
+    /// Start a process in a tty session, write and read from it
+    ///
+    /// # Example
+    ///
+    /// ```
+    ///
+    /// use rexpect::spawn;
+    /// # use rexpect::error::Error;
+    ///
+    /// # fn main() {
+    ///     # || -> Result<(), Error> {
+    /// let mut s = spawn("cat", Some(1000))?;
+    /// s.send_line("hello, polly!")?;
+    /// let line = s.read_line()?;
+    /// assert_eq!("hello, polly!", line);
+    ///         # Ok(())
+    ///     # }().expect("test failed");
+    /// # }
+    /// ```
+    pub fn new(mut process: PtyProcess, mut options: Options) -> Result<Self, Error> {
+        let modes = options.track_terminal_modes.then(|| {
+            let modes = Arc::new(Mutex::new(ModeTracker::default()));
+            options.recorders.push(modes.clone());
+            modes
+        });
+        options.start_responder(&process)?;
+        options.start_recording(process.window_size().ok());
+        let f = process.get_file_handle()?;
+        let reader = f.try_clone()?;
+        let stderr = process
+            .take_stderr()
+            .map(|stderr| StreamReader::from_file(stderr, options.reader_options()))
+            .transpose()?;
+        let stream = StreamSession::from_file(reader, f, options)?;
+        Ok(Self {
+            process,
+            stream,
+            stderr,
+            modes,
+        })
+    }
+
+    pub fn process(&self) -> &PtyProcess {
+        &self.process
+    }
+
+    pub fn process_mut(&mut self) -> &mut PtyProcess {
+        &mut self.process
+    }
+
+    /// Expect output on stderr of the process
+    ///
+    /// Returns `None` unless the process was spawned with
+    /// [`SpawnOptions::separate_stderr`](crate::process::SpawnOptions::separate_stderr).
+    /// The output on stderr isn't logged or recorded, e.g. by [`Options::log_read`]
+    /// and [`Options::record_asciicast`], which only see the output of the pty (or stdout).
+    pub fn stderr(&mut self) -> Option<&mut StreamReader> {
+        self.stderr.as_mut()
+    }
+
+    /// Split into the reading and the writing side, which can be used from different threads
+    ///
+    /// The process stays with the reading side, it is killed when that is dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rexpect::session::PtySession;
+    /// use rexpect::spawn;
+    /// use std::thread;
+    /// # use rexpect::error::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let (mut reader, mut writer) = spawn("cat", Some(1000))?.split();
+    /// let producer = thread::spawn(move || {
+    ///     writer.send_line("hello")?;
+    ///     Ok::<_, Error>(writer)
+    /// });
+    /// reader.exp_string("hello")?;
+    /// let writer = producer.join().unwrap()?;
+    /// let mut p = PtySession::reunite(reader, writer);
+    /// p.send_control('d')?;
+    /// p.exp_eof()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn split(self) -> (PtyReader, StreamWriter<File>) {
+        let (reader, writer) = self.stream.split();
+        let reader = PtyReader {
+            process: self.process,
+            reader,
+            stderr: self.stderr,
+            modes: self.modes,
+        };
+        (reader, writer)
+    }
+
+    /// Put the halves of [`Self::split`] back together
+    pub fn reunite(reader: PtyReader, writer: StreamWriter<File>) -> Self {
+        Self {
+            process: reader.process,
+            stream: StreamSession::reunite(reader.reader, writer),
+            stderr: reader.stderr,
+            modes: reader.modes,
+        }
+    }
+
+    /// Exit report of the child process if it has terminated (non-blocking).
+    ///
+    /// This is tracked independently of EOF on the pty (see [`StreamReader::is_eof`]):
+    /// the child may exit while a background process still holds the pty open,
+    /// or close its output while still running.
+    pub fn exit_report(&self) -> Option<ExitReport> {
+        self.process.exit_report()
+    }
+
+    /// Wait until the child process has terminated.
+    ///
+    /// Unlike [`StreamReader::exp_eof`] this does not wait for the pty to be closed,
+    /// which never happens as long as e.g. a background process started by the child
+    /// keeps it open.
+    ///
+    /// Return a tuple with:
+    /// 1. all the yet unread output which was read until the child exited
+    /// 2. the exit report of the child
+    pub fn exp_exit(&mut self) -> Result<(String, ExitReport), Error> {
+        exp_exit(&self.process, &mut self.stream.reader.reader)
+    }
+
+    /// See [`StreamReader::read_line`], EOF errors contain the exit report of the child
+    pub fn read_line(&mut self) -> Result<String, Error> {
+        let result = self.stream.read_line();
+        self.with_exit_report(result)
+    }
+
+    /// See [`StreamReader::exp_regex`], EOF errors contain the exit report of the child
+    pub fn exp_regex(&mut self, regex: &str) -> Result<(String, String), Error> {
+        let result = self.stream.exp_regex(regex);
+        self.with_exit_report(result)
+    }
+
+    /// See [`StreamReader::exp_string`], EOF errors contain the exit report of the child
+    pub fn exp_string(&mut self, needle: &str) -> Result<String, Error> {
+        let result = self.stream.exp_string(needle);
+        self.with_exit_report(result)
+    }
+
+    /// See [`StreamReader::exp_char`], EOF errors contain the exit report of the child
+    pub fn exp_char(&mut self, needle: char) -> Result<String, Error> {
+        let result = self.stream.exp_char(needle);
+        self.with_exit_report(result)
+    }
+
+    /// See [`StreamReader::exp_any`], EOF errors contain the exit report of the child
+    pub fn exp_any(&mut self, needles: Vec<ReadUntil>) -> Result<(String, String), Error> {
+        let result = self.stream.exp_any(needles);
+        self.with_exit_report(result)
+    }
+
+    /// See [`StreamReader::try_exp`], EOF errors contain the exit report of the child
+    pub fn try_exp(&mut self, needle: &ReadUntil) -> Result<Option<(String, String)>, Error> {
+        let result = self.stream.try_exp(needle);
+        self.with_exit_report(result)
+    }
+
+    /// Send the end of file character of the pty (`VEOF`, usually `Ctrl-D`),
+    /// like pexpect's `sendeof`
+    ///
+    /// In canonical mode this only means end of file at the start of a line,
+    /// elsewhere it passes the started line on to the child. So if the input sent last
+    /// didn't end with a newline, the character is sent twice.
+    pub fn send_eof(&mut self) -> Result<(), Error> {
+        let termios = self.process.termios()?;
+        let eof = control_character(&termios, termios::SpecialCharacterIndices::VEOF, "VEOF")?;
+        let writer = &mut self.stream.writer;
+        // the start of the line may still be buffered
+        writer.flush()?;
+        let line_end = [
+            Some(b'\n'),
+            Some(b'\r'),
+            Some(eof),
+            Some(termios.control_chars[termios::SpecialCharacterIndices::VEOL as usize]),
+        ];
+        let line_started = writer
+            .writer
+            .get_ref()
+            .last_byte()
+            .is_some_and(|last| !line_end.contains(&Some(last)));
+        if line_started && termios.local_flags.contains(termios::LocalFlags::ICANON) {
+            writer.send_bytes(&[eof, eof])
+        } else {
+            writer.send_bytes(&[eof])
+        }
+    }
+
+    /// Send the interrupt character of the pty (`VINTR`, usually `Ctrl-C`),
+    /// like pexpect's `sendintr`
+    pub fn send_intr(&mut self) -> Result<(), Error> {
+        self.send_control_character(termios::SpecialCharacterIndices::VINTR, "VINTR")
+    }
+
+    /// Send the suspend character of the pty (`VSUSP`, usually `Ctrl-Z`)
+    pub fn send_susp(&mut self) -> Result<(), Error> {
+        self.send_control_character(termios::SpecialCharacterIndices::VSUSP, "VSUSP")
+    }
+
+    /// Send `text` as if it was pasted into the terminal, i.e. wrapped in the markers of
+    /// bracketed paste mode (`ESC [ 200 ~` and `ESC [ 201 ~`)
+    ///
+    /// This waits until the child turned bracketed paste mode on in its output
+    /// (`ESC [ ? 2004 h`), at most the timeout of the session. This requires
+    /// [`Options::track_terminal_modes`], otherwise it fails with [`Error::ModesNotTracked`].
+    pub fn send_paste(&mut self, text: &str) -> Result<(), Error> {
+        self.wait_for_mode("bracketed paste mode", |modes| modes.bracketed_paste)?;
+        let mut paste = Vec::with_capacity(text.len() + 12);
+        paste.extend_from_slice(b"\x1b[200~");
+        paste.extend_from_slice(text.as_bytes());
+        paste.extend_from_slice(b"\x1b[201~");
+        self.stream.writer.send_bytes(&paste)
+    }
+
+    /// Send a mouse event at `row` and `column` (counted from 1), as xterm reports it
+    ///
+    /// This waits until the child turned mouse tracking on in its output (mode 1000,
+    /// for drags mode 1002 or 1003), at most the timeout of the session. The event is
+    /// encoded in SGR encoding if the child turned that on (mode 1006),
+    /// otherwise in the legacy encoding, which is limited to 223 rows and columns.
+    /// Like [`Self::send_paste`], this requires [`Options::track_terminal_modes`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rexpect::keys::{Mouse, MouseButton};
+    /// use rexpect::reader::Options;
+    /// use rexpect::spawn_with_options;
+    /// use std::process::Command;
+    /// # use rexpect::error::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let options = Options::new()
+    ///     .timeout_ms(Some(5000))
+    ///     .track_terminal_modes(true);
+    /// let mut p = spawn_with_options(Command::new("htop"), options)?;
+    /// p.send_mouse(Mouse::Press(MouseButton::Left), 1, 1)?;
+    /// p.send_mouse(Mouse::Release(MouseButton::Left), 1, 1)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_mouse(&mut self, event: Mouse, row: u16, column: u16) -> Result<(), Error> {
+        let required = match event {
+            Mouse::Drag(_) => MouseTracking::Drag,
+            _ => MouseTracking::Buttons,
+        };
+        let modes = self.wait_for_mode("mouse tracking mode", |modes| {
+            modes.mouse_tracking >= required
+        })?;
+        let sequence = event.encode(row, column, modes.sgr_mouse)?;
+        self.stream.writer.send_bytes(&sequence)
+    }
+
+    /// Wait until the child turned on a mode of the terminal in its output
+    fn wait_for_mode(
+        &mut self,
+        mode: &str,
+        enabled: impl Fn(&Modes) -> bool,
+    ) -> Result<Modes, Error> {
+        let Some(tracker) = &self.modes else {
+            return Err(Error::ModesNotTracked);
+        };
+        let start = time::Instant::now();
+        let reader = &mut self.stream.reader.reader;
+        loop {
+            // reading passes the output on to the tracker
+            let eof = reader.is_eof();
+            let modes = match tracker.lock() {
+                Ok(tracker) => tracker.modes(),
+                Err(poisoned) => poisoned.into_inner().modes(),
+            };
+            if enabled(&modes) {
+                return Ok(modes);
+            }
+            if eof {
+                return Err(Error::EOF {
+                    expected: mode.to_owned(),
+                    got: reader.buffer().to_owned(),
+                    exit_report: self.process.exit_report(),
+                });
+            }
+            if let Some(timeout) = reader.timeout() {
+                if start.elapsed() > timeout {
+                    return Err(Error::Timeout {
+                        expected: mode.to_owned(),
+                        got: reader.buffer().to_owned(),
+                        timeout,
+                    });
+                }
+            }
+            reader.wait(time::Duration::from_millis(10))?;
+        }
+    }
+
+    fn send_control_character(
+        &mut self,
+        index: termios::SpecialCharacterIndices,
+        name: &str,
+    ) -> Result<(), Error> {
+        let termios = self.process.termios()?;
+        let c = control_character(&termios, index, name)?;
+        self.stream.writer.send_bytes(&[c])
+    }
+
+    /// Like [`StreamSession::send_line`], but can take care of the line length limit
+    /// of the pty in canonical mode, see [`SendOptions`]
+    ///
+    /// In canonical mode (the default unless the child switches it off, e.g. to read keys)
+    /// the pty buffers a line until it is complete and drops what doesn't fit.
+    /// With [`SendOptions::split_long_lines`], longer lines are split into chunks which are
+    /// passed on to the child by the end of file character (usually `Ctrl-D`), which a child
+    /// reading the line doesn't notice. Embedded newlines start new lines, so each line is
+    /// limited separately.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rexpect::session::SendOptions;
+    /// use rexpect::spawn;
+    /// # use rexpect::error::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let mut p = spawn("head -n 1", Some(1000))?;
+    /// let options = SendOptions::new().split_long_lines(true);
+    /// p.send_line_with_options(&"x".repeat(10_000), options)?;
+    /// assert_eq!(10_000, p.read_line()?.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_line_with_options(
+        &mut self,
+        line: &str,
+        options: SendOptions,
+    ) -> Result<usize, Error> {
+        let termios = self.process.termios()?;
+        let flags = termios.local_flags;
+        let max = match flags.contains(termios::LocalFlags::ICANON) {
+            // one byte is needed for the newline or end of file character
+            true => MAX_CANON - 1,
+            false => usize::MAX,
+        };
+        let check_echo = options.check_echo && flags.contains(termios::LocalFlags::ECHO);
+        let split = match line.split('\n').map(str::len).find(|len| *len > max) {
+            Some(_) if options.split_long_lines => {
+                let eof = termios::SpecialCharacterIndices::VEOF;
+                Some((max, control_character(&termios, eof, "VEOF")?))
+            }
+            Some(len) if options.check_line_length => {
+                return Err(Error::LineTooLong { len, max });
+            }
+            _ => None,
+        };
+        // nothing is split
+        let (max, eof) = split.unwrap_or((usize::MAX, b'\n'));
+
+        self.stream.writer.delay_before_send();
+        for line in line.split('\n') {
+            let chunks = split_at_most(line, max);
+            for (i, chunk) in chunks.iter().enumerate() {
+                let end = if i + 1 == chunks.len() { b'\n' } else { eof };
+                let writer = &mut self.stream.writer;
+                writer.write_input(chunk)?;
+                writer.write_byte(end)?;
+                writer.flush()?;
+                if check_echo {
+                    self.exp_string(chunk)?;
+                }
+            }
+        }
+        Ok(line.len() + 1)
+    }
+
+    fn with_exit_report<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        with_exit_report(result, || self.process.exit_report())
+    }
+
+    /// Hand the session over to the user: forward the terminal of the current process
+    /// to the child and the output of the child to the terminal.
+    ///
+    /// This returns when the escape character (`Ctrl-]`) is typed or the child closed the pty,
+    /// see [`Self::interact_with_options`].
+    pub fn interact(&mut self) -> Result<(), Error> {
+        self.interact_with_options(InteractOptions::default())
+    }
+
+    /// Like [`Self::interact`] but with escape character and filters set in `options`
+    ///
+    /// While interacting, stdin is put into raw mode (if it is a terminal), i.e. every key is
+    /// sent to the child as it is typed, and its window size is forwarded to the pty.
+    /// Note that echo is turned off for the pty when spawning, use [`PtyProcess::set_termios`]
+    /// to turn it on if the child doesn't echo input itself.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rexpect::session::{InteractOptions, spawn_bash};
+    /// # use rexpect::error::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let mut p = spawn_bash(Some(1000))?;
+    /// p.send_line("cd /tmp")?;
+    /// p.wait_for_prompt()?;
+    /// // now the user takes over, until Ctrl-] is typed
+    /// p.interact_with_options(InteractOptions::new().output_filter(|output| {
+    ///     output.to_ascii_uppercase()
+    /// }))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn interact_with_options(&mut self, options: InteractOptions<'_>) -> Result<(), Error> {
+        let stdin = std::io::stdin();
+        let mut stdout = std::io::stdout().lock();
+        self.interact_io(stdin.as_fd(), &mut stdout, options)
+    }
+
+    fn interact_io(
+        &mut self,
+        input: BorrowedFd<'_>,
+        output: &mut dyn Write,
+        mut options: InteractOptions<'_>,
+    ) -> Result<(), Error> {
+        let _raw_mode = RawMode::enable(input)?;
+        let mut window_size = None;
+        let mut input_open = true;
+        let mut buf = [0u8; 1024];
+        loop {
+            // forward resizes of the terminal, this is polled instead of handling SIGWINCH
+            // as that would require a process wide signal handler
+            if let Ok(size) = process::window_size(input) {
+                if window_size != Some(size) {
+                    self.process.set_window_size(size.0, size.1)?;
+                    window_size = Some(size);
+                }
+            }
+
+            let reader = &mut self.stream.reader.reader;
+            let eof = reader.is_eof();
+            let bytes = reader.take_bytes();
+            if !bytes.is_empty() {
+                let bytes = match options.output_filter.as_mut() {
+                    Some(filter) => filter(&bytes),
+                    None => bytes,
+                };
+                output.write_all(&bytes)?;
+                output.flush()?;
+            }
+            if eof {
+                return Ok(());
+            }
+
+            if !input_open {
+                thread::sleep(time::Duration::from_millis(10));
+                continue;
+            }
+            let mut fds = [PollFd::new(input, PollFlags::POLLIN)];
+            match poll(&mut fds, 10u8) {
+                Ok(0) | Err(Errno::EINTR) => continue,
+                Ok(_) => {}
+                Err(err) => return Err(err.into()),
+            }
+            let n = match nix::unistd::read(input, &mut buf) {
+                Ok(n) => n,
+                Err(Errno::EINTR) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if n == 0 {
+                input_open = false;
+                continue;
+            }
+            let bytes = match options.input_filter.as_mut() {
+                Some(filter) => filter(&buf[..n]),
+                None => buf[..n].to_vec(),
+            };
+            let escape = options
+                .escape_character
+                .and_then(|escape| bytes.iter().position(|b| *b == escape));
+            let writer = &mut self.stream.writer.writer;
+            writer.write_all(&bytes[..escape.unwrap_or(bytes.len())])?;
+            writer.flush()?;
+            if escape.is_some() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The reading side of a [`PtySession`], see [`PtySession::split`]
+///
+/// Unlike the methods of [`PtySession`], EOF errors don't contain the exit report
+/// of the child, see [`Self::exit_report`].
+pub struct PtyReader {
+    process: PtyProcess,
+    reader: StreamReader,
+    stderr: Option<StreamReader>,
+    modes: Option<Arc<Mutex<ModeTracker>>>,
+}
+
+// make StreamReader's methods available directly
+impl Deref for PtyReader {
+    type Target = StreamReader;
+    fn deref(&self) -> &StreamReader {
+        &self.reader
+    }
+}
+
+impl DerefMut for PtyReader {
+    fn deref_mut(&mut self) -> &mut StreamReader {
+        &mut self.reader
+    }
+}
+
+impl PtyReader {
+    pub fn process(&self) -> &PtyProcess {
+        &self.process
+    }
+
+    pub fn process_mut(&mut self) -> &mut PtyProcess {
+        &mut self.process
+    }
+
+    /// See [`PtySession::stderr`]
+    pub fn stderr(&mut self) -> Option<&mut StreamReader> {
+        self.stderr.as_mut()
+    }
+
+    /// See [`PtySession::exit_report`]
+    pub fn exit_report(&self) -> Option<ExitReport> {
+        self.process.exit_report()
+    }
+
+    /// See [`PtySession::exp_exit`]
+    pub fn exp_exit(&mut self) -> Result<(String, ExitReport), Error> {
+        exp_exit(&self.process, &mut self.reader.reader)
+    }
+}
+
+/// How long [`exp_exit`] keeps reading after the child exited, in case a background
+/// process keeps the pty open and EOF never arrives
+pub(crate) const EXIT_OUTPUT_GRACE: time::Duration = time::Duration::from_millis(100);
+
+/// Fill in the exit report of EOF errors
+fn with_exit_report<T>(
+    result: Result<T, Error>,
+    exit_report: impl Fn() -> Option<ExitReport>,
+) -> Result<T, Error> {
+    match result {
+        Err(Error::EOF {
+            expected,
+            got,
+            exit_report: None,
+        }) => Err(Error::EOF {
+            expected,
+            got,
+            exit_report: wait_for_exit_report(exit_report),
+        }),
+        result => result,
+    }
+}
+
+/// The output is usually closed because the child exits,
+/// give it a moment to actually terminate
+fn wait_for_exit_report(exit_report: impl Fn() -> Option<ExitReport>) -> Option<ExitReport> {
+    for _ in 0..10 {
+        if let Some(report) = exit_report() {
+            return Some(report);
+        }
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    None
+}
+
+fn exp_exit(process: &PtyProcess, reader: &mut NBReader) -> Result<(String, ExitReport), Error> {
+    let start = time::Instant::now();
+    loop {
+        // keep reading, the child may block on a full pty otherwise
+        let eof = reader.is_eof();
+        if let Some(status) = process.exit_report() {
+            // the last output of the child may still be in the pty
+            let exited = time::Instant::now();
+            while !reader.is_eof() && exited.elapsed() < EXIT_OUTPUT_GRACE {
+                reader.wait(time::Duration::from_millis(10))?;
+            }
+            return Ok((reader.take_buffer(), status));
+        }
+
+        if let Some(timeout) = reader.timeout() {
+            if start.elapsed() > timeout {
+                // make sure that `got` contains everything read until now
+                reader.is_eof();
+                return Err(Error::Timeout {
+                    expected: "child process exit".to_owned(),
+                    got: reader.buffer().to_owned(),
+                    timeout,
+                });
+            }
+        }
+        if eof {
+            // nothing to wait for on the pty anymore
+            thread::sleep(time::Duration::from_millis(10));
+        } else {
+            reader.wait(time::Duration::from_millis(10))?;
+        }
+    }
+}
+
+/// The master side of the pty, e.g. to wait for output in an own event loop
+///
+/// Unless the session was created with [`Options::poll_reader`], the output is read
+/// by a background thread, which races with the event loop for the readiness.
+impl AsFd for PtySession {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.process.as_fd()
+    }
+}
+
+impl AsRawFd for PtySession {
+    fn as_raw_fd(&self) -> RawFd {
+        self.process.as_raw_fd()
+    }
+}
+
+#[cfg(feature = "mio")]
+#[cfg_attr(docsrs, doc(cfg(feature = "mio")))]
+impl mio::event::Source for PtySession {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> std::io::Result<()> {
+        self.process.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> std::io::Result<()> {
+        self.process.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> std::io::Result<()> {
+        self.process.deregister(registry)
+    }
+}
+
+/// The control character `index` of `termios`, fails if it is disabled
+fn control_character(
+    termios: &Termios,
+    index: termios::SpecialCharacterIndices,
+    name: &str,
+) -> Result<u8, Error> {
+    let c = termios.control_chars[index as usize];
+    if c == nix::libc::_POSIX_VDISABLE {
+        return Err(Error::DisabledControlCharacter(name.to_owned()));
+    }
+    Ok(c)
+}
+
+/// Number of bytes the pty buffers for a line in canonical mode
+#[cfg(target_os = "linux")]
+const MAX_CANON: usize = 4096;
+#[cfg(not(target_os = "linux"))]
+const MAX_CANON: usize = 1024;
+
+/// Split `line` into chunks of at most `max` bytes, at char boundaries
+fn split_at_most(mut line: &str, max: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    while line.len() > max {
+        let mut end = max;
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, rest) = line.split_at(end);
+        chunks.push(chunk);
+        line = rest;
+    }
+    chunks.push(line);
+    chunks
+}
+
+/// Options for [`PtySession::send_line_with_options`]
+///
+/// By default the line is sent like [`StreamSession::send_line`] does.
+#[derive(Default)]
+pub struct SendOptions {
+    split_long_lines: bool,
+    check_line_length: bool,
+    check_echo: bool,
+}
+
+impl SendOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Split lines which are too long for canonical mode
+    pub fn split_long_lines(mut self, yes: bool) -> Self {
+        self.split_long_lines = yes;
+        self
+    }
+
+    /// Fail with [`Error::LineTooLong`] before anything is sent, if a line is too long
+    /// for canonical mode and isn't split
+    pub fn check_line_length(mut self, yes: bool) -> Self {
+        self.check_line_length = yes;
+        self
+    }
+
+    /// Wait until each chunk is echoed, to make sure the child received all of it
+    ///
+    /// The echo is consumed, except for the line ending. This is skipped if echo is off
+    /// for the pty, and fails if the line contains control characters (which are echoed
+    /// differently).
+    pub fn check_echo(mut self, yes: bool) -> Self {
+        self.check_echo = yes;
+        self
+    }
+}
+
+/// Options for [`PtySession::interact_with_options`]
+pub struct InteractOptions<'a> {
+    escape_character: Option<u8>,
+    input_filter: Option<Filter<'a>>,
+    output_filter: Option<Filter<'a>>,
+}
+
+type Filter<'a> = Box<dyn FnMut(&[u8]) -> Vec<u8> + 'a>;
+
+impl Default for InteractOptions<'_> {
+    fn default() -> Self {
+        Self {
+            // Ctrl-], like telnet and pexpect
+            escape_character: Some(0x1d),
+            input_filter: None,
+            output_filter: None,
+        }
+    }
+}
+
+impl<'a> InteractOptions<'a> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Return control when this byte is typed, `None` forwards everything to the child
+    ///
+    /// The default is `Ctrl-]` (`0x1d`).
+    pub fn escape_character(mut self, escape_character: Option<u8>) -> Self {
+        self.escape_character = escape_character;
+        self
+    }
+
+    /// Transform the input of the user before it is sent to the child
+    ///
+    /// The escape character is looked for in the transformed input.
+    pub fn input_filter(mut self, filter: impl FnMut(&[u8]) -> Vec<u8> + 'a) -> Self {
+        self.input_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Transform the output of the child before it is shown to the user
+    pub fn output_filter(mut self, filter: impl FnMut(&[u8]) -> Vec<u8> + 'a) -> Self {
+        self.output_filter = Some(Box::new(filter));
+        self
+    }
+}
+
+/// Puts a terminal into raw mode, restores its attributes when dropped
+struct RawMode<'fd> {
+    fd: BorrowedFd<'fd>,
+    original: Termios,
+}
+
+impl<'fd> RawMode<'fd> {
+    /// Returns `None` if `fd` isn't a terminal
+    fn enable(fd: BorrowedFd<'fd>) -> Result<Option<Self>, Error> {
+        if !fd.is_terminal() {
+            return Ok(None);
+        }
+        let original = termios::tcgetattr(fd)?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(fd, termios::SetArg::TCSANOW, &raw)?;
+        Ok(Some(Self { fd, original }))
+    }
+}
+
+impl Drop for RawMode<'_> {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(self.fd, termios::SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// Interact with a process through pipes instead of a pty, see [`PipeProcess`]
+///
+/// # Example
+///
+/// ```
+/// use rexpect::session::spawn_pipe;
+/// use rexpect::reader::Options;
+/// use std::process::Command;
+/// # use rexpect::error::Error;
+///
+/// # fn main() -> Result<(), Error> {
+/// let mut s = spawn_pipe(Command::new("cat"), Options::new().timeout_ms(Some(1000)))?;
+/// s.send_line("hello, polly!")?;
+/// let line = s.read_line()?;
+/// assert_eq!("hello, polly!", line);
+/// # Ok(())
+/// # }
+/// ```
+pub struct PipeSession {
+    process: PipeProcess,
+    stream: StreamSession<ChildStdin>,
+    stderr: Option<StreamReader>,
+}
+
+// make StreamSession's methods available directly
+impl Deref for PipeSession {
+    type Target = StreamSession<ChildStdin>;
+    fn deref(&self) -> &StreamSession<ChildStdin> {
+        &self.stream
+    }
+}
+
+impl DerefMut for PipeSession {
+    fn deref_mut(&mut self) -> &mut StreamSession<ChildStdin> {
+        &mut self.stream
+    }
+}
+
+impl PipeSession {
+    pub fn new(mut process: PipeProcess, options: Options) -> Result<Self, Error> {
+        let (Some(stdin), Some(output)) = (process.take_stdin(), process.take_output()) else {
+            return Err(Error::BrokenPipe);
+        };
+        let stderr = process
+            .take_stderr()
+            .map(|stderr| StreamReader::from_file(stderr, options.reader_options()))
+            .transpose()?;
+        let stream = StreamSession::from_file(output, stdin, options)?;
+        Ok(Self {
+            process,
+            stream,
+            stderr,
+        })
+    }
+
+    pub fn process(&self) -> &PipeProcess {
+        &self.process
+    }
+
+    pub fn process_mut(&mut self) -> &mut PipeProcess {
+        &mut self.process
+    }
+
+    /// See [`PtySession::stderr`]
+    pub fn stderr(&mut self) -> Option<&mut StreamReader> {
+        self.stderr.as_mut()
+    }
+
+    /// Exit report of the child process if it has terminated (non-blocking).
+    pub fn exit_report(&self) -> Option<ExitReport> {
+        self.process.exit_report()
+    }
+
+    /// See [`StreamReader::read_line`], EOF errors contain the exit report of the child
+    pub fn read_line(&mut self) -> Result<String, Error> {
+        let result = self.stream.read_line();
+        self.with_exit_report(result)
+    }
+
+    /// See [`StreamReader::exp_regex`], EOF errors contain the exit report of the child
+    pub fn exp_regex(&mut self, regex: &str) -> Result<(String, String), Error> {
+        let result = self.stream.exp_regex(regex);
+        self.with_exit_report(result)
+    }
+
+    /// See [`StreamReader::exp_string`], EOF errors contain the exit report of the child
+    pub fn exp_string(&mut self, needle: &str) -> Result<String, Error> {
+        let result = self.stream.exp_string(needle);
+        self.with_exit_report(result)
+    }
+
+    /// See [`StreamReader::exp_char`], EOF errors contain the exit report of the child
+    pub fn exp_char(&mut self, needle: char) -> Result<String, Error> {
+        let result = self.stream.exp_char(needle);
+        self.with_exit_report(result)
+    }
+
+    /// See [`StreamReader::exp_any`], EOF errors contain the exit report of the child
+    pub fn exp_any(&mut self, needles: Vec<ReadUntil>) -> Result<(String, String), Error> {
+        let result = self.stream.exp_any(needles);
+        self.with_exit_report(result)
     }
 
-    // wrapper around reader::read_until to give more context for errors
-    fn exp(&mut self, needle: &ReadUntil) -> Result<(String, String), Error> {
-        self.reader.read_until(needle)
+    /// See [`StreamReader::try_exp`], EOF errors contain the exit report of the child
+    pub fn try_exp(&mut self, needle: &ReadUntil) -> Result<Option<(String, String)>, Error> {
+        let result = self.stream.try_exp(needle);
+        self.with_exit_report(result)
+    }
+
+    fn with_exit_report<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        with_exit_report(result, || self.process.exit_report())
     }
 }
 
-/// Interact with a process with read/write/signals, etc.
-#[allow(dead_code)]
-pub struct PtySession {
-    process: PtyProcess,
+/// Run expect on a file descriptor which is already open, e.g. the master of a pty
+/// created by another library, a serial device or an inherited socket
+///
+/// If the file descriptor is a terminal, its attributes and window size can be changed as well.
+///
+/// # Example
+///
+/// ```no_run
+/// use rexpect::session::FdSession;
+/// use rexpect::reader::Options;
+/// use std::fs::OpenOptions;
+/// # use rexpect::error::Error;
+///
+/// # fn main() -> Result<(), Error> {
+/// let device = OpenOptions::new().read(true).write(true).open("/dev/ttyUSB0")?;
+/// let mut s = FdSession::new(device.into(), Options::new().timeout_ms(Some(5000)))?;
+/// s.send_line("AT")?;
+/// s.exp_string("OK")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct FdSession {
+    fd: OwnedFd,
     stream: StreamSession<File>,
 }
 
 // make StreamSession's methods available directly
-impl Deref for PtySession {
+impl Deref for FdSession {
     type Target = StreamSession<File>;
     fn deref(&self) -> &StreamSession<File> {
         &self.stream
     }
 }
 
-impl DerefMut for PtySession {
+impl DerefMut for FdSession {
     fn deref_mut(&mut self) -> &mut StreamSession<File> {
         &mut self.stream
     }
 }
 
-impl PtySession {
-    /// Start a process in a tty session, write and read from it
-    ///
-    /// # Example
-    ///
-    /// ```
-    ///
-    /// use rexpect::spawn;
-    /// # use rexpect::error::Error;
-    ///
-    /// # fn main() {
-    ///     # || -> Result<(), Error> {
-    /// let mut s = spawn("cat", Some(1000))?;
-    /// s.send_line("hello, polly!")?;
-    /// let line = s.read_line()?;
-    /// assert_eq!("hello, polly!", line);
-    ///         # Ok(())
-    ///     # }().expect("test failed");
-    /// # }
-    /// ```
-    pub fn new(process: PtyProcess, options: Options) -> Result<Self, Error> {
-        let f = process.get_file_handle()?;
+impl FdSession {
+    /// Take over `fd` for reading and writing
+    pub fn new(fd: OwnedFd, options: Options) -> Result<Self, Error> {
+        options.start_recording(process::window_size(fd.as_fd()).ok());
+        let f = File::from(fd.try_clone()?);
         let reader = f.try_clone()?;
-        let stream = StreamSession::new(reader, f, options);
-        Ok(Self { process, stream })
+        let stream = StreamSession::from_file(reader, f, options)?;
+        Ok(Self { fd, stream })
     }
 
-    pub fn process(&self) -> &PtyProcess {
-        &self.process
+    /// Whether the file descriptor refers to a terminal
+    pub fn is_tty(&self) -> bool {
+        self.fd.as_fd().is_terminal()
     }
 
-    pub fn process_mut(&mut self) -> &mut PtyProcess {
-        &mut self.process
+    /// Terminal attributes, fails if the file descriptor is no terminal
+    pub fn termios(&self) -> Result<Termios, Error> {
+        termios::tcgetattr(&self.fd).map_err(Error::from)
+    }
+
+    /// Change the terminal attributes (immediately)
+    pub fn set_termios(&mut self, termios: &Termios) -> Result<(), Error> {
+        termios::tcsetattr(&self.fd, termios::SetArg::TCSANOW, termios).map_err(Error::from)
+    }
+
+    /// Window size of the terminal as `(rows, columns)`
+    pub fn window_size(&self) -> Result<(u16, u16), Error> {
+        process::window_size(self.fd.as_fd()).map_err(Error::from)
+    }
+
+    /// Resize the terminal
+    pub fn set_window_size(&mut self, rows: u16, cols: u16) -> Result<(), Error> {
+        process::set_window_size(self.fd.as_fd(), rows, cols).map_err(Error::from)
     }
 }
 
@@ -258,16 +1666,92 @@ pub fn spawn_command(command: Command, timeout_ms: Option<u64>) -> Result<PtySes
 
 /// See [`spawn`]
 pub fn spawn_with_options(command: Command, options: Options) -> Result<PtySession, Error> {
+    spawn_with_spawn_options(command, options, SpawnOptions::default())
+}
+
+/// See [`spawn`], the limits and settings of `spawn_options` are applied to the child process
+///
+/// # Example
+///
+/// ```
+/// use rexpect::process::SpawnOptions;
+/// use rexpect::reader::Options;
+/// use rexpect::session::spawn_with_spawn_options;
+/// use std::process::Command;
+/// # use rexpect::error::Error;
+///
+/// # fn main() -> Result<(), Error> {
+/// let mut command = Command::new("sh");
+/// command.args(["-c", "ulimit -n"]);
+/// let options = Options::new().timeout_ms(Some(1000));
+/// let mut p = spawn_with_spawn_options(command, options, SpawnOptions::new().max_open_files(42))?;
+/// p.exp_string("42")?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn spawn_with_spawn_options(
+    mut command: Command,
+    mut options: Options,
+    spawn_options: SpawnOptions,
+) -> Result<PtySession, Error> {
+    match &options.term {
+        Some(term) => {
+            command.env("TERM", term);
+        }
+        // encode keys for the terminal type the child sees
+        None => options.term = child_env(&command, "TERM"),
+    }
+    options.recorded_env = Some(recorded_env(&command));
     #[cfg(feature = "which")]
     {
         let _ = which::which(command.get_program())?;
     }
-    let mut process = PtyProcess::new(command)?;
+    let mut process = PtyProcess::with_options(command, spawn_options)?;
     process.set_kill_timeout(options.timeout_ms);
 
     PtySession::new(process, options)
 }
 
+/// The value of the environment variable `name` which `command` passes on to the child
+fn child_env(command: &Command, name: &str) -> Option<String> {
+    match command.get_envs().find(|(key, _)| *key == name) {
+        Some((_, value)) => value.map(|value| value.to_string_lossy().into_owned()),
+        None => std::env::var(name).ok(),
+    }
+}
+
+/// The environment variables of the child which are recorded, see [`Options::record_asciicast`]
+pub(crate) fn recorded_env(command: &Command) -> Vec<(String, String)> {
+    RECORDED_ENV
+        .iter()
+        .filter_map(|&name| Some((name.to_owned(), child_env(command, name)?)))
+        .collect()
+}
+
+/// Start command in background connected to pipes instead of a pty,
+/// see [`PipeProcess`] and [`spawn_with_options`]
+pub fn spawn_pipe(command: Command, options: Options) -> Result<PipeSession, Error> {
+    spawn_pipe_with_spawn_options(command, options, SpawnOptions::default())
+}
+
+/// See [`spawn_pipe`], the limits and settings of `spawn_options` are applied to the child
+/// process, e.g. to separate stderr from stdout
+pub fn spawn_pipe_with_spawn_options(
+    command: Command,
+    mut options: Options,
+    spawn_options: SpawnOptions,
+) -> Result<PipeSession, Error> {
+    options.recorded_env = Some(recorded_env(&command));
+    #[cfg(feature = "which")]
+    {
+        let _ = which::which(command.get_program())?;
+    }
+    let mut process = PipeProcess::with_options(command, spawn_options)?;
+    process.set_kill_timeout(options.timeout_ms);
+
+    PipeSession::new(process, options)
+}
+
 /// A repl session: e.g. bash or the python shell.
 ///
 /// You have a prompt where a user inputs commands and the shell
@@ -422,6 +1906,11 @@ impl Drop for PtyReplSession {
 ///
 /// For an example see the README
 pub fn spawn_bash(timeout: Option<u64>) -> Result<PtyReplSession, Error> {
+    spawn_bash_with_options(Options::new().timeout_ms(timeout))
+}
+
+/// See [`spawn_bash`]
+pub fn spawn_bash_with_options(options: Options) -> Result<PtyReplSession, Error> {
     // unfortunately working with a temporary tmpfile is the only
     // way to guarantee that we are "in step" with the prompt
     // all other attempts were futile, especially since we cannot
@@ -442,7 +1931,7 @@ pub fn spawn_bash(timeout: Option<u64>) -> Result<PtyReplSession, Error> {
         "--rcfile",
         rcfile.path().to_str().unwrap_or("temp file does not exist"),
     ]);
-    spawn_command(c, timeout).and_then(|p| {
+    spawn_with_options(c, options).and_then(|p| {
         let new_prompt = "[REXPECT_PROMPT>";
         let mut pb = PtyReplSession {
             pty_session: p,
@@ -472,6 +1961,11 @@ pub fn spawn_python(timeout: Option<u64>) -> Result<PtyReplSession, Error> {
     })
 }
 
+/// Run expect on an already open file descriptor, see [`FdSession`]
+pub fn spawn_fd(fd: OwnedFd, timeout_ms: Option<u64>) -> Result<FdSession, Error> {
+    FdSession::new(fd, Options::new().timeout_ms(timeout_ms))
+}
+
 /// Spawn a REPL from a stream
 pub fn spawn_stream<R: Read + Send + 'static, W: Write>(
     reader: R,
@@ -484,16 +1978,18 @@ pub fn spawn_stream<R: Read + Send + 'static, W: Write>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use nix::sys::wait;
+    use crate::process::{Resource, Signal, WaitStatus};
+    use crate::reader::Matcher;
+    use crate::terminal::TerminalProfile;
+    use nix::fcntl::{FcntlArg, OFlag, fcntl};
 
     #[test]
     fn test_read_line() -> Result<(), Error> {
         let mut s = spawn("cat", Some(100000))?;
         s.send_line("hans")?;
         assert_eq!("hans", s.read_line()?);
-        let should =
-            wait::WaitStatus::Signaled(s.process.child_pid, crate::process::Signal::SIGTERM, false);
-        assert_eq!(should, s.process.exit()?);
+        let should = WaitStatus::Signaled(s.process.child_pid(), Signal::SIGTERM, false);
+        assert_eq!(should, s.process.exit()?.status());
         Ok(())
     }
 
@@ -514,6 +2010,742 @@ mod tests {
         assert!(p.exp_eof().is_ok(), "expected eof");
     }
 
+    #[test]
+    fn test_expect_exit_with_open_pty() -> Result<(), Error> {
+        // the background sleep (ignoring the SIGHUP on exit of sh) keeps the pty open
+        let mut p = spawn(
+            "sh -c 'trap \"\" HUP; sleep 5 & echo started; exit 3'",
+            Some(2000),
+        )?;
+        let (output, report) = p.exp_exit()?;
+        assert_eq!(Some(3), report.exit_code());
+        assert!(output.contains("started"), "unexpected output {output:?}");
+        assert!(!p.is_eof());
+        assert_eq!(Some(report), p.exit_report());
+        Ok(())
+    }
+
+    #[test]
+    fn test_expect_exit_reads_remaining_output() -> Result<(), Error> {
+        // more output than fits into the pty, so most of it is still unread when seq exits
+        let mut p = spawn("seq 1 20000", Some(5000))?;
+        let (output, report) = p.exp_exit()?;
+        assert!(report.success());
+        assert!(output.ends_with("19999\r\n20000\r\n"));
+
+        let mut command = Command::new("seq");
+        command.args(["1", "20000"]);
+        let options = Options::new().timeout_ms(Some(5000)).poll_reader(true);
+        let mut p = spawn_with_options(command, options)?;
+        let (output, _) = p.exp_exit()?;
+        assert!(output.ends_with("20000\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_eof_while_child_alive() -> Result<(), Error> {
+        let mut p = spawn("sh -c 'exec >&- 2>&- <&-; sleep 1'", Some(2000))?;
+        p.exp_eof()?;
+        assert!(p.is_eof());
+        assert_eq!(None, p.exit_report());
+        Ok(())
+    }
+
+    #[test]
+    fn test_eof_error_contains_exit_report() {
+        let mut p = spawn("sh -c 'echo bye; exit 7'", Some(2000)).expect("cannot run sh");
+        match p.exp_string("hello") {
+            Err(Error::EOF {
+                exit_report: Some(report),
+                ..
+            }) => assert_eq!(Some(7), report.exit_code()),
+            result => panic!("should raise EOF with exit report, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn test_resource_limits() -> Result<(), Error> {
+        let spawn_options = SpawnOptions::new().max_open_files(42).max_cpu_time(1);
+        let options = Options::new().timeout_ms(Some(5000));
+        let mut p = spawn_with_spawn_options(Command::new("sh"), options, spawn_options)?;
+        p.send_line("ulimit -n")?;
+        p.exp_string("42")?;
+        p.send_line("while :; do :; done")?;
+        let (_, report) = p.exp_exit()?;
+        assert_eq!(Some(Signal::SIGXCPU), report.signal());
+        assert_eq!(Some(Resource::RLIMIT_CPU), report.limit_exceeded());
+        assert!(report.to_string().contains("CPU time limit exceeded"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_spawn_failure_in_child() -> Result<(), Error> {
+        // the soft limit must not be above the hard limit
+        let spawn_options = SpawnOptions::new().rlimit(Resource::RLIMIT_NOFILE, 10, 5);
+        let options = Options::new().timeout_ms(Some(2000));
+        let mut p = spawn_with_spawn_options(Command::new("cat"), options, spawn_options)?;
+        p.exp_string("rexpect: cannot set a resource limit: Invalid argument")?;
+        let (_, report) = p.exp_exit()?;
+        assert_eq!(Some(127), report.exit_code());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fd_hygiene() -> Result<(), Error> {
+        // only this fd is inheritable, so that it doesn't leak into children of other tests
+        let (unrelated, _) = process::cloexec_pipe()?;
+        fcntl(&unrelated, FcntlArg::F_SETFD(nix::fcntl::FdFlag::empty()))?;
+        // the writer is close-on-exec, it is inherited only as fd 5
+        let (reader, writer) = process::cloexec_pipe()?;
+        let options = Options::new().timeout_ms(Some(2000));
+        let spawn_options = SpawnOptions::new().pass_fd(writer, 5);
+        let script = format!(
+            "test -e /dev/fd/{} && echo leaked || echo closed; echo passed >&5",
+            unrelated.as_raw_fd()
+        );
+        let mut command = Command::new("sh");
+        command.args(["-c", &script]);
+
+        let mut p = spawn_with_spawn_options(command, options, spawn_options)?;
+        p.exp_string("closed")?;
+        let mut passed = String::new();
+        File::from(reader).read_to_string(&mut passed)?;
+        assert_eq!("passed\n", passed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fd_session_on_pty() -> Result<(), Error> {
+        let pty = nix::pty::openpty(None, None)?;
+        let mut s = spawn_fd(pty.master, Some(1000))?;
+        assert!(s.is_tty());
+
+        let mut slave = File::from(pty.slave);
+        slave.write_all(b"hello from the other side\n")?;
+        s.exp_string("other side")?;
+
+        s.set_window_size(42, 123)?;
+        assert_eq!((42, 123), process::window_size(slave.as_fd())?);
+        assert_eq!((42, 123), s.window_size()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fd_session_on_pipe() -> Result<(), Error> {
+        let (reader, writer) = nix::unistd::pipe()?;
+        let mut s = spawn_fd(reader, Some(1000))?;
+        assert!(!s.is_tty());
+        assert!(s.termios().is_err());
+
+        File::from(writer).write_all(b"no tty here")?;
+        assert_eq!("no tty here", s.exp_eof()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pipe_session() -> Result<(), Error> {
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "test -t 0 || echo no tty; read line; echo \"got $line\" >&2; exit 4",
+        ]);
+        let mut p = spawn_pipe(command, Options::new().timeout_ms(Some(1000)))?;
+        p.exp_string("no tty")?;
+        p.send_line("hello")?;
+        p.exp_string("got hello")?;
+        match p.exp_string("never") {
+            Err(Error::EOF {
+                exit_report: Some(report),
+                ..
+            }) => assert_eq!(Some(4), report.exit_code()),
+            result => panic!("should raise EOF with exit report, got {result:?}"),
+        }
+        assert_eq!(Some(4), p.process().wait()?.exit_code());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_timeout() -> Result<(), Error> {
+        let options = Options::new()
+            .timeout_ms(Some(1000))
+            .write_timeout_ms(Some(100));
+        let mut command = Command::new("sleep");
+        command.arg("5");
+        let mut p = spawn_pipe(command, options)?;
+        let input = "x".repeat(1 << 16);
+        let start = time::Instant::now();
+        // fill the pipe, sleep doesn't read it
+        let error = loop {
+            assert!(start.elapsed() < time::Duration::from_secs(10));
+            if let Err(error) = p.send(&input) {
+                break error;
+            }
+        };
+        match error {
+            Error::Io(err) if err.kind() == std::io::ErrorKind::TimedOut => {}
+            error => panic!("expected a write timeout, got {error:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_timeout_pty() -> Result<(), Error> {
+        let options = Options::new()
+            .timeout_ms(Some(5000))
+            .write_timeout_ms(Some(1000));
+        let mut command = Command::new("sh");
+        command.args(["-c", "stty raw; echo ready; head -c 262144 | wc -c"]);
+        let mut p = spawn_with_options(command, options)?;
+        p.exp_string("ready")?;
+        // more than the pty buffers, so it is written while the process reads
+        p.send(&"x".repeat(1 << 18))?;
+        p.flush()?;
+        p.exp_string(&format!("{}", 1 << 18))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_writer_thread() -> Result<(), Error> {
+        let options = Options::new().timeout_ms(Some(2000)).writer_thread(true);
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 0.2; head -c 1048576 | wc -c"]);
+        let mut p = spawn_pipe(command, options)?;
+        let input = "x".repeat(1 << 20);
+        let start = time::Instant::now();
+        p.send(&input)?;
+        // the process doesn't read for a while, but sending doesn't wait for it
+        p.flush()?;
+        assert!(start.elapsed() >= time::Duration::from_millis(100));
+        assert_eq!(format!("{}", 1 << 20), p.read_line()?.trim());
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_writer_thread() -> Result<(), Error> {
+        let (output, _) = process::cloexec_pipe()?;
+        let (input, writer) = process::cloexec_pipe()?;
+        let options = Options::new().writer_thread(true);
+        let mut session = StreamSession::from_file(output.into(), File::from(writer), options)?;
+        // nobody reads the input, so the thread waits for the pipe to become writable
+        session.send(&"x".repeat(1 << 20))?;
+        drop(session);
+        // the thread ended and closed its end of the pipe
+        let mut written = Vec::new();
+        File::from(input).read_to_end(&mut written)?;
+        assert!(written.len() < 1 << 20);
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_long_line() -> Result<(), Error> {
+        let line = "ä".repeat(5_000);
+        let mut command = Command::new("sh");
+        command.args(["-c", "head -n 1 | wc -c"]);
+        let mut p = spawn_with_options(command, Options::new().timeout_ms(Some(1000)))?;
+        match p.send_line_with_options(&line, SendOptions::new().check_line_length(true)) {
+            Err(Error::LineTooLong { len: 10_000, .. }) => {}
+            result => panic!("expected the line to be too long, got {result:?}"),
+        }
+        let split = || SendOptions::new().split_long_lines(true);
+        let mut termios = p.process().termios()?;
+        termios.control_chars[termios::SpecialCharacterIndices::VEOF as usize] =
+            nix::libc::_POSIX_VDISABLE;
+        p.process_mut().set_termios(&termios)?;
+        match p.send_line_with_options(&line, split()) {
+            Err(Error::DisabledControlCharacter(_)) => {}
+            result => panic!("expected the end of file character to be disabled, got {result:?}"),
+        }
+        termios.control_chars[termios::SpecialCharacterIndices::VEOF as usize] = 4;
+        p.process_mut().set_termios(&termios)?;
+        assert_eq!(10_001, p.send_line_with_options(&line, split())?);
+        assert_eq!("10001", p.read_line()?.trim());
+
+        // by default the line is sent as it is, and the pty truncates it
+        let mut command = Command::new("sh");
+        command.args(["-c", "head -n 1 | wc -c"]);
+        let mut p = spawn_with_options(command, Options::new().timeout_ms(Some(1000)))?;
+        assert_eq!(10_001, p.send_line(&line)?);
+        assert_ne!("10001", p.read_line()?.trim());
+
+        // with echo
+        let mut command = Command::new("sh");
+        command.args(["-c", "read line; echo ${#line}"]);
+        let mut p = spawn_with_options(command, Options::new().timeout_ms(Some(1000)))?;
+        let mut termios = p.process().termios()?;
+        termios.local_flags |= termios::LocalFlags::ECHO;
+        p.process_mut().set_termios(&termios)?;
+        let line = "x".repeat(10_000);
+        let options = SendOptions::new().split_long_lines(true).check_echo(true);
+        p.send_line_with_options(&line, options)?;
+        assert_eq!("", p.read_line()?);
+        assert_eq!("10000", p.read_line()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_at_most() {
+        assert_eq!(vec![""], split_at_most("", 2));
+        assert_eq!(vec!["ab", "c"], split_at_most("abc", 2));
+        assert_eq!(vec!["a", "ä", "b"], split_at_most("aäb", 2));
+    }
+
+    #[test]
+    fn test_typing_delay() -> Result<(), Error> {
+        let (session, mut process) = crate::mock::duplex();
+        let (reader, writer) = session.split();
+        let options = Options::new()
+            .timeout_ms(Some(1000))
+            .delay_before_send_ms(Some(50))
+            .typing_delay_ms(Some(10), 5);
+        let mut s = StreamSession::new(reader, writer, options);
+        let start = time::Instant::now();
+        s.send_line("abc")?;
+        assert!(start.elapsed() >= time::Duration::from_millis(50 + 4 * 10));
+        let mut input = [0; 4];
+        process.read_exact(&mut input)?;
+        assert_eq!(b"abc\n", &input);
+
+        s.set_delay_before_send_ms(None);
+        s.set_typing_delay_ms(None, 0);
+        s.send_line("abc")?;
+        process.read_exact(&mut input)?;
+        assert_eq!(b"abc\n", &input);
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_keys() -> Result<(), Error> {
+        let (session, mut process) = crate::mock::duplex();
+        let (reader, writer) = session.split();
+        let mut s = StreamSession::new(reader, writer, Options::new().term("screen"));
+        s.send_keys("a<Home><C-e>")?;
+        s.send_key(Key::F(1))?;
+        let mut input = [0; 9];
+        process.read_exact(&mut input)?;
+        assert_eq!(b"a\x1b[1~\x05\x1bOP", &input);
+        assert!(matches!(s.send_keys("<Nope>"), Err(Error::InvalidKey(_))));
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo $TERM"]);
+        let options = Options::new().timeout_ms(Some(1000)).term("vt100");
+        let mut p = spawn_with_options(command, options)?;
+        assert_eq!("vt100", p.read_line()?);
+
+        // the keys are encoded for the TERM of the child
+        let mut command = Command::new("cat");
+        command.arg("-v").env("TERM", "rxvt");
+        let mut p = spawn_with_options(command, Options::new().timeout_ms(Some(1000)))?;
+        p.send_keys("<Home><Enter>")?;
+        assert_eq!("^[[7~", p.read_line()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_answer_terminal_queries() -> Result<(), Error> {
+        for poll_reader in [false, true] {
+            let mut command = Command::new("sh");
+            command.args([
+                "-c",
+                r"stty raw -echo; printf 'abc\033[6n'; head -c 6 | tr '\033' E; printf '\r\n\033[18t'; head -c 10 | tr '\033' E",
+            ]);
+            let options = Options::new()
+                .timeout_ms(Some(1000))
+                .poll_reader(poll_reader)
+                .answer_terminal_queries(TerminalProfile::new());
+            let mut p = spawn_with_options(command, options)?;
+            p.exp_string("abc")?;
+            p.exp_string("E[1;4R")?;
+            // the size of the pty wasn't set
+            p.exp_string("E[8;24;80t")?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_undeliverable_reply() -> Result<(), Error> {
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            r"stty raw -echo; echo ready; sleep 1; printf '\033[6n'; echo after; sleep 2",
+        ]);
+        let options = Options::new()
+            .timeout_ms(Some(2000))
+            .write_timeout_ms(Some(100))
+            .answer_terminal_queries(TerminalProfile::new());
+        let mut p = spawn_with_options(command, options)?;
+        p.exp_string("ready")?;
+        // fill the input of the pty until not even a reply fits, sh doesn't read it
+        let mut input = p.process().get_file_handle()?;
+        let flags = OFlag::from_bits_truncate(fcntl(&input, FcntlArg::F_GETFL)?);
+        fcntl(&input, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+        while input.write(b"x").is_ok() {}
+        fcntl(&input, FcntlArg::F_SETFL(flags))?;
+        // the reply to the query is dropped instead of blocking the reader
+        p.exp_string("after")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_paste() -> Result<(), Error> {
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            r"stty raw -echo; printf '\033[?2004h'; head -c 14 | tr '\033' E",
+        ]);
+        let options = || {
+            Options::new()
+                .timeout_ms(Some(1000))
+                .track_terminal_modes(true)
+        };
+        let mut p = spawn_with_options(command, options())?;
+        p.send_paste("hi")?;
+        p.exp_string("E[200~hiE[201~")?;
+
+        // cat doesn't turn on bracketed paste mode
+        let mut p = spawn_with_options(Command::new("cat"), options().timeout_ms(Some(300)))?;
+        assert!(matches!(p.send_paste("hi"), Err(Error::Timeout { .. })));
+
+        let mut p = spawn("cat", Some(300))?;
+        assert!(matches!(p.send_paste("hi"), Err(Error::ModesNotTracked)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_mouse() -> Result<(), Error> {
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            r"stty raw -echo; printf '\033[?1000h\033[?1006h'; head -c 18 | tr '\033' E",
+        ]);
+        let options = Options::new()
+            .timeout_ms(Some(500))
+            .track_terminal_modes(true);
+        let mut p = spawn_with_options(command, options)?;
+        // dragging needs mode 1002
+        let drag = p.send_mouse(Mouse::Drag(keys::MouseButton::Left), 2, 3);
+        assert!(matches!(drag, Err(Error::Timeout { .. })));
+        p.send_mouse(Mouse::Press(keys::MouseButton::Left), 2, 3)?;
+        p.send_mouse(Mouse::Release(keys::MouseButton::Left), 2, 3)?;
+        p.exp_string("E[<0;3;2ME[<0;3;2m")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_eof() -> Result<(), Error> {
+        // a started line is passed on first
+        let mut p = spawn("cat", Some(1000))?;
+        p.send("abc")?;
+        p.send_eof()?;
+        assert_eq!("abc", p.exp_eof()?);
+
+        // with a different end of file character
+        let mut p = spawn("cat", Some(1000))?;
+        let mut termios = p.process().termios()?;
+        termios.control_chars[termios::SpecialCharacterIndices::VEOF as usize] = 0x18;
+        p.process_mut().set_termios(&termios)?;
+        p.send_line("hello")?;
+        assert_eq!("hello", p.read_line()?);
+        p.send_eof()?;
+        p.exp_eof()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_intr() -> Result<(), Error> {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo ready; exec sleep 5"]);
+        let mut p = spawn_with_options(command, Options::new().timeout_ms(Some(1000)))?;
+        let mut termios = p.process().termios()?;
+        termios.control_chars[termios::SpecialCharacterIndices::VINTR as usize] = 0x18;
+        p.process_mut().set_termios(&termios)?;
+        // the pty is the controlling terminal of the child by now
+        p.exp_string("ready")?;
+        p.send_intr()?;
+        let (_, report) = p.exp_exit()?;
+        assert_eq!(Some(Signal::SIGINT), report.signal());
+
+        termios.control_chars[termios::SpecialCharacterIndices::VSUSP as usize] =
+            nix::libc::_POSIX_VDISABLE;
+        let mut command = Command::new("sleep");
+        command.arg("5");
+        let mut p = spawn_with_options(command, Options::new().timeout_ms(Some(1000)))?;
+        p.process_mut().set_termios(&termios)?;
+        assert!(matches!(
+            p.send_susp(),
+            Err(Error::DisabledControlCharacter(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_separate_stderr() -> Result<(), Error> {
+        let options = Options::new().timeout_ms(Some(1000));
+        let spawn_options = SpawnOptions::new().separate_stderr(true);
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo err >&2; echo out; read line; echo done"]);
+        let mut p = spawn_with_spawn_options(command, options, spawn_options)?;
+        p.exp_string("out")?;
+        p.stderr().expect("stderr is separate").exp_string("err")?;
+        p.send_line("")?;
+        let before = p.exp_string("done")?;
+        assert!(!before.contains("err"));
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo out; echo err >&2"]);
+        let options = Options::new().timeout_ms(Some(1000));
+        let spawn_options = SpawnOptions::new().separate_stderr(true);
+        let mut p = spawn_pipe_with_spawn_options(command, options, spawn_options)?;
+        assert_eq!("out", p.read_line()?);
+        assert_eq!("err", p.stderr().expect("stderr is separate").read_line()?);
+        p.exp_eof()?;
+
+        let mut p = spawn("cat", Some(1000))?;
+        assert!(p.stderr().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_interact() -> Result<(), Error> {
+        // input is forwarded until the escape character
+        let (input, input_writer) = nix::unistd::pipe()?;
+        nix::unistd::write(&input_writer, b"hello\n\x1dignored\n")?;
+        let mut p = spawn("cat", Some(1000))?;
+        let mut output = Vec::new();
+        p.interact_io(
+            input.as_fd(),
+            &mut output,
+            InteractOptions::new().input_filter(|input| input.to_ascii_uppercase()),
+        )?;
+        assert_eq!("HELLO", p.read_line()?);
+        p.send_line("bye")?;
+        assert_eq!("bye", p.read_line()?);
+
+        // output is forwarded until EOF
+        let (input, _input_writer) = nix::unistd::pipe()?;
+        let mut p = spawn("echo hello", Some(1000))?;
+        let mut output = Vec::new();
+        p.interact_io(
+            input.as_fd(),
+            &mut output,
+            InteractOptions::new().output_filter(|output| output.to_ascii_uppercase()),
+        )?;
+        assert_eq!(b"HELLO\r\n", output.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_log() -> Result<(), Error> {
+        let log = tempfile::NamedTempFile::new()?;
+        let log_send = tempfile::NamedTempFile::new()?;
+        let options = Options::new()
+            .timeout_ms(Some(1000))
+            .log(log.reopen()?)
+            .log_send(log_send.reopen()?);
+        let mut p = spawn_with_options(Command::new("cat"), options)?;
+        p.send_line("hello")?;
+        assert_eq!("hello", p.read_line()?);
+        p.send("wor")?;
+        p.send_line("ld")?;
+        assert_eq!("world", p.read_line()?);
+        assert_eq!(
+            "> hello\n< hello\r\n> world\n< world\r\n",
+            std::fs::read_to_string(log.path())?
+        );
+        assert_eq!("hello\nworld\n", std::fs::read_to_string(log_send.path())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_asciicast() -> Result<(), Error> {
+        let cast = tempfile::NamedTempFile::new()?;
+        let options = Options::new()
+            .timeout_ms(Some(1000))
+            .term("vt100")
+            .record_asciicast(cast.reopen()?);
+        let mut p = spawn_with_options(Command::new("cat"), options)?;
+        p.send_line("hello")?;
+        assert_eq!("hello", p.read_line()?);
+
+        let content = std::fs::read_to_string(cast.path())?;
+        assert!(content.starts_with(r#"{"version": 2, "width": 80, "height": 24, "#));
+        // the environment of the child
+        assert!(content.contains(r#""TERM": "vt100"}"#), "{content}");
+        assert!(content.contains(r#", "i", "hello\n"]"#), "{content}");
+        assert!(content.contains(r#", "o", "hello"#), "{content}");
+
+        let cast = tempfile::NamedTempFile::new()?;
+        let options = Options::new()
+            .timeout_ms(Some(1000))
+            .record_asciicast(cast.reopen()?);
+        let mut p = spawn_with_options(Command::new("sh"), options)?;
+        p.send_line("echo recorded")?;
+        p.exp_string("recorded")?;
+        let content = std::fs::read_to_string(cast.path())?;
+        assert!(
+            content.contains(r#", "i", "echo recorded\n"]"#),
+            "{content}"
+        );
+
+        let cast = tempfile::NamedTempFile::new()?;
+        let options = Options::new()
+            .timeout_ms(Some(1000))
+            .record_asciicast(cast.reopen()?);
+        let mut p = spawn_bash_with_options(options)?;
+        p.execute("echo recorded", "recorded")?;
+        let content = std::fs::read_to_string(cast.path())?;
+        assert!(content.contains(r#""TERM": ""}"#), "{content}");
+        assert!(
+            content.contains(r#", "i", "echo recorded\n"]"#),
+            "{content}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_expect() -> Result<(), Error> {
+        let mut first = spawn("cat", Some(1000))?;
+        let mut second = spawn("cat", Some(1000))?;
+        second.send_line("world")?;
+        let (index, before, matched) = MultiExpect::new(Some(1000))
+            .add(&mut first, ReadUntil::String("hello".to_owned()))
+            .add(&mut second, ReadUntil::String("world".to_owned()))
+            .exp()?;
+        assert_eq!(
+            (1, String::new(), "world".to_owned()),
+            (index, before, matched)
+        );
+
+        let result = MultiExpect::new(Some(100))
+            .add(&mut first, ReadUntil::String("hello".to_owned()))
+            .add(&mut second, ReadUntil::String("world".to_owned()))
+            .exp();
+        match result {
+            Err(Error::Timeout { expected, .. }) => {
+                assert_eq!("0: \"hello\", 1: \"world\"", expected);
+            }
+            result => panic!("expected a timeout, got {result:?}"),
+        }
+
+        // a session at EOF doesn't stop waiting for the others
+        let mut exiting = spawn("sh -c 'exit 5'", Some(1000))?;
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 0.2; echo later"]);
+        let options = Options::new().timeout_ms(Some(1000)).poll_reader(true);
+        let mut later = spawn_with_options(command, options)?;
+        let (index, _, matched) = MultiExpect::new(Some(1000))
+            .add(&mut exiting, ReadUntil::String("never".to_owned()))
+            .add(&mut later, ReadUntil::String("later".to_owned()))
+            .exp()?;
+        assert_eq!((1, "later".to_owned()), (index, matched));
+
+        let mut exiting = spawn("sh -c 'exit 5'", Some(1000))?;
+        let mut also_exiting = spawn("sh -c 'sleep 0.1; exit 6'", Some(1000))?;
+        let result = MultiExpect::new(Some(1000))
+            .add(&mut exiting, ReadUntil::String("never".to_owned()))
+            .add(&mut also_exiting, ReadUntil::String("never".to_owned()))
+            .exp();
+        match result {
+            Err(Error::EOF {
+                exit_report: Some(report),
+                ..
+            }) => assert_eq!(Some(5), report.exit_code()),
+            result => panic!("should raise EOF with exit report, got {result:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_reader() -> Result<(), Error> {
+        let options = || Options::new().timeout_ms(Some(1000)).poll_reader(true);
+        let mut p = spawn_with_options(Command::new("cat"), options())?;
+        p.send_line("hello")?;
+        assert_eq!("hello", p.read_line()?);
+        match p.exp_string("never") {
+            Err(Error::Timeout { .. }) => {}
+            result => panic!("expected a timeout, got {result:?}"),
+        }
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 0.2; echo done"]);
+        let mut p = spawn_with_options(command, options())?;
+        assert_eq!("done\r\n", p.exp_eof()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_feed_matcher() -> Result<(), Error> {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo ready; sleep 0.1; echo done"]);
+        let process = PtyProcess::new(command)?;
+        let mut matcher = Matcher::new(Options::default());
+        let needle = ReadUntil::String("done".to_owned());
+        let mut buf = [0; 1024];
+        let matched = loop {
+            let mut fds = [PollFd::new(process.as_fd(), PollFlags::POLLIN)];
+            assert_ne!(0, poll(&mut fds, 1000u16)?, "timeout");
+            match nix::unistd::read(&process, &mut buf) {
+                Ok(n) => matcher.feed(&buf[..n]),
+                // the pty is closed
+                Err(Errno::EIO) => matcher.feed_eof(),
+                Err(err) => return Err(err.into()),
+            }
+            if let Some(matched) = matcher.try_match(&needle)? {
+                break matched;
+            }
+        };
+        assert_eq!(("ready\r\n".to_owned(), "done".to_owned()), matched);
+        Ok(())
+    }
+
+    #[cfg(feature = "mio")]
+    #[test]
+    fn test_mio_source() -> Result<(), Error> {
+        let options = Options::new().timeout_ms(Some(1000)).poll_reader(true);
+        let mut p = spawn_with_options(Command::new("cat"), options)?;
+        let mut poll = mio::Poll::new()?;
+        let mut events = mio::Events::with_capacity(4);
+        poll.registry()
+            .register(&mut p, mio::Token(0), mio::Interest::READABLE)?;
+        p.send_line("hello")?;
+        let needle = ReadUntil::String("hello".to_owned());
+        loop {
+            poll.poll(&mut events, Some(time::Duration::from_secs(1)))?;
+            assert!(!events.is_empty(), "timeout");
+            if p.try_exp(&needle)?.is_some() {
+                break;
+            }
+        }
+        poll.registry().deregister(&mut p)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_split() -> Result<(), Error> {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let (mut reader, mut writer) = spawn("cat", Some(1000))?.split();
+        assert_send(&reader);
+        assert_send(&writer);
+        let producer = thread::spawn(move || -> Result<_, Error> {
+            for i in 0..10 {
+                writer.send_line(&format!("line {i}"))?;
+            }
+            Ok(writer)
+        });
+        for i in 0..10 {
+            assert_eq!(format!("line {i}"), reader.read_line()?);
+        }
+        let writer = producer.join().expect("producer panicked")?;
+
+        let mut p = PtySession::reunite(reader, writer);
+        p.send_line("again")?;
+        assert_eq!("again", p.read_line()?);
+        p.send_control('d')?;
+        let (_, report) = p.exp_exit()?;
+        assert!(report.success());
+        Ok(())
+    }
+
     #[test]
     fn test_expect_string() -> Result<(), Error> {
         let mut p = spawn("cat", Some(1000)).expect("cannot run cat");
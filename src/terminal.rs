@@ -0,0 +1,533 @@
+//! Follows the control sequences in the output of a process, e.g. to know which
+//! input modes of the terminal it turned on, and answers the queries of the process
+//! in place of a terminal, see [`TerminalProfile`]
+
+use crate::log::{Direction, Record};
+use std::io::{self, Write};
+
+/// Longest parameter string of a control sequence which is kept, longer ones are ignored
+const MAX_PARAMS: usize = 64;
+
+/// A control sequence (CSI) like `ESC [ ? 2004 h`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Csi {
+    /// Private marker before the parameters, e.g. `?`
+    pub(crate) prefix: Option<u8>,
+    pub(crate) params: String,
+    pub(crate) final_byte: u8,
+}
+
+impl Csi {
+    /// The numeric parameters, separated by `;`
+    pub(crate) fn numbers(&self) -> impl Iterator<Item = u16> + '_ {
+        self.params
+            .split(';')
+            .filter_map(|param| param.parse().ok())
+    }
+
+    /// The numeric parameter at `index`, `default` if it's missing or 0
+    fn number(&self, index: usize, default: u16) -> u16 {
+        match self.params.split(';').nth(index).map(str::parse) {
+            Some(Ok(0)) | Some(Err(_)) | None => default,
+            Some(Ok(n)) => n,
+        }
+    }
+}
+
+/// What the output of a process consists of
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Token {
+    /// A byte which isn't part of a sequence, i.e. text or a control character
+    Byte(u8),
+    Csi(Csi),
+    /// An operating system command like `ESC ] 11 ; ? BEL`
+    Osc {
+        data: String,
+        /// Whether it was terminated by `BEL` instead of `ESC \`
+        bel: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    Csi,
+    Osc,
+    /// Escape within an operating system command, which ends it if `\` follows
+    OscEscape,
+}
+
+/// Finds the control sequences in a stream of bytes, which may be split anywhere
+#[derive(Debug, Default)]
+pub(crate) struct Parser {
+    state: State,
+    prefix: Option<u8>,
+    params: String,
+    /// Whether the sequence is too long to be of interest
+    overflow: bool,
+}
+
+impl Parser {
+    /// Returns the token which is completed by `byte`
+    pub(crate) fn feed(&mut self, byte: u8) -> Option<Token> {
+        match (self.state, byte) {
+            (State::Osc, 0x1b) => self.state = State::OscEscape,
+            (State::Osc, 0x07) => return self.finish_osc(true),
+            (State::OscEscape, b'\\') => return self.finish_osc(false),
+            (State::OscEscape, _) => {
+                // a new sequence started
+                self.state = State::Escape;
+                return self.feed(byte);
+            }
+            (_, 0x1b) => self.state = State::Escape,
+            (State::Ground, _) => return Some(Token::Byte(byte)),
+            (State::Escape, b'[' | b']') => {
+                self.state = if byte == b'[' { State::Csi } else { State::Osc };
+                self.prefix = None;
+                self.params.clear();
+                self.overflow = false;
+            }
+            // intermediate bytes, e.g. of `ESC ( B`
+            (State::Escape, 0x20..=0x2f) => {}
+            (State::Escape, _) => self.state = State::Ground,
+            (State::Csi, b'<'..=b'?') if self.params.is_empty() && self.prefix.is_none() => {
+                self.prefix = Some(byte);
+            }
+            (State::Csi, 0x20..=0x3f) | (State::Osc, 0x20..) => self.push(byte),
+            (State::Csi, 0x40..=0x7e) => {
+                self.state = State::Ground;
+                if !self.overflow {
+                    return Some(Token::Csi(Csi {
+                        prefix: self.prefix,
+                        params: std::mem::take(&mut self.params),
+                        final_byte: byte,
+                    }));
+                }
+            }
+            (State::Csi | State::Osc, _) => self.state = State::Ground,
+        }
+        None
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.params.len() < MAX_PARAMS {
+            self.params.push(char::from(byte));
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    fn finish_osc(&mut self, bel: bool) -> Option<Token> {
+        self.state = State::Ground;
+        if self.overflow {
+            return None;
+        }
+        Some(Token::Osc {
+            data: std::mem::take(&mut self.params),
+            bel,
+        })
+    }
+}
+
+/// How the terminal reports mouse events, as requested by the process
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum MouseTracking {
+    #[default]
+    Off,
+    /// Presses and releases (mode 1000)
+    Buttons,
+    /// Also motion while a button is pressed (mode 1002)
+    Drag,
+    /// Also any motion (mode 1003)
+    Any,
+}
+
+/// The input modes of the terminal which the process turned on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Modes {
+    pub(crate) bracketed_paste: bool,
+    pub(crate) mouse_tracking: MouseTracking,
+    /// Whether mouse events are reported in SGR encoding (mode 1006)
+    pub(crate) sgr_mouse: bool,
+}
+
+impl Modes {
+    fn apply(&mut self, csi: &Csi) {
+        let on = match (csi.prefix, csi.final_byte) {
+            (Some(b'?'), b'h') => true,
+            (Some(b'?'), b'l') => false,
+            _ => return,
+        };
+        for mode in csi.numbers() {
+            match mode {
+                2004 => self.bracketed_paste = on,
+                1000 | 1002 | 1003 => {
+                    let tracking = match mode {
+                        1000 => MouseTracking::Buttons,
+                        1002 => MouseTracking::Drag,
+                        _ => MouseTracking::Any,
+                    };
+                    if on {
+                        self.mouse_tracking = tracking;
+                    } else if self.mouse_tracking == tracking {
+                        self.mouse_tracking = MouseTracking::Off;
+                    }
+                }
+                1006 => self.sgr_mouse = on,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Follows the modes set in the output of the process
+#[derive(Debug, Default)]
+pub(crate) struct ModeTracker {
+    parser: Parser,
+    modes: Modes,
+}
+
+impl ModeTracker {
+    pub(crate) fn modes(&self) -> Modes {
+        self.modes
+    }
+}
+
+impl Record for ModeTracker {
+    fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        if direction == Direction::Read {
+            for &byte in data {
+                if let Some(Token::Csi(csi)) = self.parser.feed(byte) {
+                    self.modes.apply(&csi);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The terminal which answers the queries of a process,
+/// see [`Options::answer_terminal_queries`](crate::reader::Options::answer_terminal_queries)
+///
+/// These queries are answered:
+///
+/// - cursor position (`ESC [ 6 n`): the position set with [`Self::cursor_position`],
+///   otherwise where the output of the process moved the cursor to. This follows text,
+///   line breaks and cursor movements, but e.g. not scrolling regions or wide characters.
+/// - device status (`ESC [ 5 n`): ok
+/// - primary device attributes (`ESC [ c`), see [`Self::device_attributes`],
+///   and secondary device attributes (`ESC [ > c`)
+/// - size of the text area (`ESC [ 18 t`), see [`Self::size`]
+/// - foreground and background color (`ESC ] 10 ; ? BEL` and `ESC ] 11 ; ? BEL`),
+///   see [`Self::colors`]
+#[derive(Clone, Debug)]
+pub struct TerminalProfile {
+    size: Option<(u16, u16)>,
+    cursor_position: Option<(u16, u16)>,
+    device_attributes: String,
+    foreground: (u8, u8, u8),
+    background: (u8, u8, u8),
+}
+
+impl Default for TerminalProfile {
+    fn default() -> Self {
+        Self {
+            size: None,
+            cursor_position: None,
+            // a VT220 with colors
+            device_attributes: "?62;22".to_owned(),
+            foreground: (0xff, 0xff, 0xff),
+            background: (0, 0, 0),
+        }
+    }
+}
+
+impl TerminalProfile {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Size in rows and columns, by default the size of the pty
+    /// (or 24x80 if it has none, i.e. it's still 0x0)
+    pub fn size(mut self, rows: u16, columns: u16) -> Self {
+        self.size = Some((rows, columns));
+        self
+    }
+
+    /// Always report the cursor at `row` and `column` (counted from 1)
+    pub fn cursor_position(mut self, row: u16, column: u16) -> Self {
+        self.cursor_position = Some((row, column));
+        self
+    }
+
+    /// Parameters of the reply to the primary device attributes query,
+    /// the default `?62;22` is a VT220 with colors
+    pub fn device_attributes(mut self, params: impl Into<String>) -> Self {
+        self.device_attributes = params.into();
+        self
+    }
+
+    /// Foreground and background color as RGB, by default white on black
+    pub fn colors(mut self, foreground: (u8, u8, u8), background: (u8, u8, u8)) -> Self {
+        self.foreground = foreground;
+        self.background = background;
+        self
+    }
+}
+
+/// Where the output of the process moved the cursor to, counted from 0
+#[derive(Debug, Default)]
+struct Cursor {
+    row: u16,
+    column: u16,
+    /// Whether the last column was written, so the next character goes to the next line
+    wrap: bool,
+}
+
+impl Cursor {
+    fn byte(&mut self, byte: u8, (rows, columns): (u16, u16)) {
+        match byte {
+            b'\x08' => self.move_to(self.row, self.column.saturating_sub(1)),
+            b'\t' => self.move_to(self.row, (self.column / 8 + 1) * 8),
+            b'\n' | b'\x0b' | b'\x0c' => self.move_to(self.row + 1, self.column),
+            b'\r' => self.move_to(self.row, 0),
+            // continuation bytes of UTF-8 characters
+            0x00..=0x1f | 0x7f | 0x80..=0xbf => {}
+            _ => {
+                if self.wrap {
+                    self.move_to(self.row + 1, 0);
+                }
+                if self.column + 1 < columns {
+                    self.column += 1;
+                } else {
+                    self.wrap = true;
+                }
+            }
+        }
+        self.clamp(rows, columns);
+    }
+
+    fn csi(&mut self, csi: &Csi, (rows, columns): (u16, u16)) {
+        if csi.prefix.is_some() {
+            return;
+        }
+        let n = csi.number(0, 1);
+        let (row, column) = (self.row, self.column);
+        match csi.final_byte {
+            b'A' => self.move_to(row.saturating_sub(n), column),
+            b'B' => self.move_to(row.saturating_add(n), column),
+            b'C' => self.move_to(row, column.saturating_add(n)),
+            b'D' => self.move_to(row, column.saturating_sub(n)),
+            b'E' => self.move_to(row.saturating_add(n), 0),
+            b'F' => self.move_to(row.saturating_sub(n), 0),
+            b'G' => self.move_to(row, n - 1),
+            b'd' => self.move_to(n - 1, column),
+            b'H' | b'f' => self.move_to(n - 1, csi.number(1, 1) - 1),
+            _ => {}
+        }
+        self.clamp(rows, columns);
+    }
+
+    fn move_to(&mut self, row: u16, column: u16) {
+        self.row = row;
+        self.column = column;
+        self.wrap = false;
+    }
+
+    fn clamp(&mut self, rows: u16, columns: u16) {
+        self.row = self.row.min(rows.saturating_sub(1));
+        self.column = self.column.min(columns.saturating_sub(1));
+    }
+}
+
+/// Answers the queries in the output of the process by writing to its input
+pub(crate) struct Responder<W> {
+    profile: TerminalProfile,
+    size: (u16, u16),
+    parser: Parser,
+    cursor: Cursor,
+    input: W,
+}
+
+impl<W: Write> Responder<W> {
+    pub(crate) fn new(profile: TerminalProfile, input: W) -> Self {
+        Self {
+            size: profile.size.unwrap_or((24, 80)),
+            profile,
+            parser: Parser::default(),
+            cursor: Cursor::default(),
+            input,
+        }
+    }
+
+    fn reply(&self, token: &Token) -> Option<String> {
+        let reply = match token {
+            Token::Byte(_) => return None,
+            Token::Csi(csi) => match (csi.prefix, csi.final_byte, csi.params.as_str()) {
+                (None, b'n', "5") => "\x1b[0n".to_owned(),
+                (None, b'n', "6") => {
+                    let (row, column) = self
+                        .profile
+                        .cursor_position
+                        .unwrap_or((self.cursor.row + 1, self.cursor.column + 1));
+                    format!("\x1b[{row};{column}R")
+                }
+                (None, b'c', "" | "0") => format!("\x1b[{}c", self.profile.device_attributes),
+                (Some(b'>'), b'c', "" | "0") => "\x1b[>0;0;0c".to_owned(),
+                (None, b't', "18") => format!("\x1b[8;{};{}t", self.size.0, self.size.1),
+                _ => return None,
+            },
+            Token::Osc { data, bel } => {
+                let (r, g, b) = match data.as_str() {
+                    "10;?" => self.profile.foreground,
+                    "11;?" => self.profile.background,
+                    _ => return None,
+                };
+                let terminator = if *bel { "\x07" } else { "\x1b\\" };
+                // the color is reported with 16 bits per channel
+                format!(
+                    "\x1b]{};rgb:{r:02x}{r:02x}/{g:02x}{g:02x}/{b:02x}{b:02x}{terminator}",
+                    &data[..2]
+                )
+            }
+        };
+        Some(reply)
+    }
+}
+
+impl<W: Write + Send> Record for Responder<W> {
+    fn start(
+        &mut self,
+        window_size: Option<(u16, u16)>,
+        _env: Option<&[(String, String)]>,
+    ) -> io::Result<()> {
+        // a pty starts with 0 rows and columns until someone sets its size
+        if let (None, Some((rows, columns))) = (self.profile.size, window_size) {
+            if rows > 0 && columns > 0 {
+                self.size = (rows, columns);
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        if direction != Direction::Read {
+            return Ok(());
+        }
+        for &byte in data {
+            let Some(token) = self.parser.feed(byte) else {
+                continue;
+            };
+            match &token {
+                Token::Byte(byte) => self.cursor.byte(*byte, self.size),
+                Token::Csi(csi) => self.cursor.csi(csi, self.size),
+                Token::Osc { .. } => {}
+            }
+            if let Some(reply) = self.reply(&token) {
+                // a process which doesn't read its input doesn't get the reply, but the
+                // output is still followed
+                let _ = self
+                    .input
+                    .write_all(reply.as_bytes())
+                    .and_then(|()| self.input.flush());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parser() {
+        let mut parser = Parser::default();
+        let mut found = Vec::new();
+        for &byte in b"a\x1b[31m\x1b[?2004;1006h\x1b(B\x1b]11;?\x07\x1b]0;title\x1b\\\x1b[>c" {
+            found.extend(parser.feed(byte));
+        }
+        let csi = |prefix, params: &str, final_byte| {
+            Token::Csi(Csi {
+                prefix,
+                params: params.to_owned(),
+                final_byte,
+            })
+        };
+        let osc = |data: &str, bel| Token::Osc {
+            data: data.to_owned(),
+            bel,
+        };
+        assert_eq!(
+            vec![
+                Token::Byte(b'a'),
+                csi(None, "31", b'm'),
+                csi(Some(b'?'), "2004;1006", b'h'),
+                osc("11;?", true),
+                osc("0;title", false),
+                csi(Some(b'>'), "", b'c'),
+            ],
+            found
+        );
+    }
+
+    #[test]
+    fn test_mode_tracker() -> io::Result<()> {
+        let mut tracker = ModeTracker::default();
+        tracker.record(Direction::Read, b"\x1b[?2004h\x1b[?10")?;
+        tracker.record(Direction::Read, b"02;1006h")?;
+        assert_eq!(
+            Modes {
+                bracketed_paste: true,
+                mouse_tracking: MouseTracking::Drag,
+                sgr_mouse: true,
+            },
+            tracker.modes()
+        );
+        // input doesn't change the modes
+        tracker.record(Direction::Send, b"\x1b[?2004l")?;
+        tracker.record(Direction::Read, b"\x1b[?1000l\x1b[?1002l")?;
+        assert!(tracker.modes().bracketed_paste);
+        assert_eq!(MouseTracking::Off, tracker.modes().mouse_tracking);
+        Ok(())
+    }
+
+    #[test]
+    fn test_responder() -> io::Result<()> {
+        let mut responder = Responder::new(TerminalProfile::new(), Vec::new());
+        responder.start(Some((10, 5)), None)?;
+        // the last column is filled, the cursor wraps with the next character
+        responder.record(Direction::Read, b"ab\r\nhello\x1b[6n")?;
+        responder.record(Direction::Read, b"!\x1b[6n\x1b[2;3H\x1b[A\x1b[6n")?;
+        responder.record(Direction::Read, b"\x1b[c\x1b[18t\x1b]11;?\x1b\\\x1b[5n")?;
+        assert_eq!(
+            "\x1b[2;5R\x1b[3;2R\x1b[1;3R\x1b[?62;22c\x1b[8;10;5t\x1b]11;rgb:0000/0000/0000\x1b\\\x1b[0n",
+            String::from_utf8_lossy(&responder.input)
+        );
+
+        let profile = TerminalProfile::new()
+            .cursor_position(3, 4)
+            .device_attributes("?1;2")
+            .colors((0x12, 0x34, 0x56), (0xff, 0xff, 0xff));
+        let mut responder = Responder::new(profile, Vec::new());
+        responder.record(Direction::Read, b"\x1b[6n\x1b[0c\x1b]10;?\x07")?;
+        // input isn't answered
+        responder.record(Direction::Send, b"\x1b[6n")?;
+        assert_eq!(
+            "\x1b[3;4R\x1b[?1;2c\x1b]10;rgb:1212/3434/5656\x07",
+            String::from_utf8_lossy(&responder.input)
+        );
+
+        // the size of a pty which wasn't set is unknown
+        let mut responder = Responder::new(TerminalProfile::new(), Vec::new());
+        responder.start(Some((0, 0)), None)?;
+        responder.record(Direction::Read, b"abc\x1b[6n\x1b[18t")?;
+        assert_eq!(
+            "\x1b[1;4R\x1b[8;24;80t",
+            String::from_utf8_lossy(&responder.input)
+        );
+        Ok(())
+    }
+}
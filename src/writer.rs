@@ -0,0 +1,393 @@
+//! Writing to the process without blocking forever, see [`Options::write_timeout_ms`]
+//! and [`Options::writer_thread`]
+
+use crate::process::cloexec_pipe;
+use crate::reader::Options;
+use nix::errno::Errno;
+use nix::libc;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sys::stat::{SFlag, fstat};
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::os::fd::{AsFd, OwnedFd};
+use std::sync::mpsc::{Sender, channel};
+use std::sync::{Arc, Condvar, Mutex};
+use std::{thread, time};
+
+/// Where a session writes its input to
+pub(crate) enum Output<W> {
+    /// Straight to the writer, blocks until the process accepts the input
+    Direct(W),
+    /// Waits at most the write timeout for the process to accept input
+    Poll(PollWriter),
+    /// Queued for a background thread
+    Thread(WriterThread),
+}
+
+impl<W: Into<OwnedFd>> Output<W> {
+    /// Pick how to write to `writer` according to `options`
+    pub(crate) fn from_fd(writer: W, options: &Options) -> io::Result<Self> {
+        let timeout = options.write_timeout_ms.map(time::Duration::from_millis);
+        if options.writer_thread {
+            return Ok(Self::Thread(WriterThread::new(writer.into(), timeout)?));
+        }
+        match timeout {
+            Some(timeout) => Ok(Self::Poll(PollWriter::new(writer.into(), timeout)?)),
+            None => Ok(Self::Direct(writer)),
+        }
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Direct(writer) => writer.write(buf),
+            Self::Poll(writer) => writer.write(buf),
+            Self::Thread(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Direct(writer) => writer.flush(),
+            Self::Poll(writer) => writer.flush(),
+            Self::Thread(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Pauses between the characters of typed input, see [`Options::typing_delay_ms`]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Typing {
+    delay: time::Duration,
+    jitter: time::Duration,
+}
+
+impl Typing {
+    pub(crate) fn new(delay_ms: u64, jitter_ms: u64) -> Self {
+        Self {
+            delay: time::Duration::from_millis(delay_ms),
+            jitter: time::Duration::from_millis(jitter_ms),
+        }
+    }
+
+    /// Wait after a character was typed
+    pub(crate) fn pause(&self) {
+        thread::sleep(self.delay + random_up_to(self.jitter));
+    }
+}
+
+/// Not meant to be unpredictable, just to vary the spacing of typed characters
+fn random_up_to(max: time::Duration) -> time::Duration {
+    if max.is_zero() {
+        return max;
+    }
+    // every `RandomState` is seeded differently
+    let random = RandomState::new().build_hasher().finish();
+    let max = max.as_nanos().min(u128::from(u64::MAX - 1)) as u64;
+    time::Duration::from_nanos(random % (max + 1))
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "the process didn't accept input in time",
+    )
+}
+
+/// Whether `file` accepts input, waits at most `timeout`
+///
+/// Errors like a closed reading end count as writable, writing reports them. A hang up
+/// fails instead: a pty whose other side was closed isn't writable, but blocks a write
+/// forever once its buffer is full.
+fn writable(file: &File, timeout: time::Duration) -> io::Result<bool> {
+    let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+    let mut fds = [PollFd::new(file.as_fd(), PollFlags::POLLOUT)];
+    match poll(&mut fds, timeout) {
+        Ok(0) | Err(Errno::EINTR) => Ok(false),
+        Ok(_) if fds[0].revents().is_some_and(hung_up) => Err(io::ErrorKind::BrokenPipe.into()),
+        Ok(_) => Ok(true),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn hung_up(revents: PollFlags) -> bool {
+    revents.contains(PollFlags::POLLHUP) && !revents.contains(PollFlags::POLLOUT)
+}
+
+/// How many bytes are written to a pty at once
+const PTY_CHUNK_SIZE: usize = 1024;
+
+/// How many bytes to write to `file` at once when it is writable
+///
+/// A writable pipe has room for `PIPE_BUF` bytes. A pty only promises room for one byte,
+/// but writing it byte by byte costs two syscalls per byte. So a chunk of [`PTY_CHUNK_SIZE`]
+/// is written, which blocks until the process read enough if the pty has less room.
+fn chunk_size(file: &File) -> io::Result<usize> {
+    let mode = SFlag::from_bits_truncate(fstat(file)?.st_mode);
+    if mode & SFlag::S_IFMT == SFlag::S_IFIFO {
+        Ok(libc::PIPE_BUF)
+    } else {
+        Ok(PTY_CHUNK_SIZE)
+    }
+}
+
+/// Write chunks of `buf` to the writable `file` as long as it stays writable
+fn write_writable(file: &mut File, size: usize, buf: &[u8]) -> io::Result<usize> {
+    let mut written = 0;
+    while written < buf.len() {
+        let end = buf.len().min(written + size);
+        match file.write(&buf[written..end]) {
+            Ok(0) => break,
+            Ok(n) => written += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) if written == 0 => return Err(err),
+            Err(_) => break,
+        }
+        if !writable(file, time::Duration::ZERO)? {
+            break;
+        }
+    }
+    Ok(written)
+}
+
+/// Waits until the process accepts input before writing it, fails if no input
+/// is accepted within the timeout
+///
+/// The file descriptor stays blocking, so other users of it (e.g. the reader of a pty,
+/// which shares the open file description) aren't affected. Only a chunk is written
+/// at once, see [`chunk_size`].
+pub(crate) struct PollWriter {
+    file: File,
+    size: usize,
+    timeout: time::Duration,
+}
+
+impl PollWriter {
+    fn new(fd: OwnedFd, timeout: time::Duration) -> io::Result<Self> {
+        let file = File::from(fd);
+        Ok(Self {
+            size: chunk_size(&file)?,
+            file,
+            timeout,
+        })
+    }
+}
+
+impl Write for PollWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = time::Instant::now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            if writable(&self.file, remaining)? {
+                return write_writable(&mut self.file, self.size, buf);
+            }
+            if remaining.is_zero() {
+                return Err(timed_out());
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes to a file descriptor which may be non-blocking (e.g. shared with an async session),
+/// waits until it is writable instead of failing with `WouldBlock`, but at most the timeout
+pub(crate) struct WaitingWriter {
+    file: File,
+    timeout: time::Duration,
+}
+
+impl WaitingWriter {
+    pub(crate) fn new(file: File, timeout: time::Duration) -> Self {
+        Self { file, timeout }
+    }
+}
+
+impl Write for WaitingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let deadline = time::Instant::now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            // a blocking file is polled as well, so a process which doesn't read can't block
+            if writable(&self.file, remaining)? {
+                match self.file.write(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+            }
+            if remaining.is_zero() {
+                return Err(timed_out());
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Queues the input for a background thread, so writing never blocks
+///
+/// Errors of the thread are returned by the next write or flush.
+/// Dropping this wakes the thread up and waits until it ended.
+pub(crate) struct WriterThread {
+    tx: Option<Sender<Vec<u8>>>,
+    queue: Arc<Queue>,
+    handle: Option<thread::JoinHandle<()>>,
+    /// Closing this wakes the thread up if it waits for the process to accept input
+    waker: Option<OwnedFd>,
+    timeout: Option<time::Duration>,
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    /// Notified when input was written or writing failed
+    changed: Condvar,
+}
+
+struct QueueState {
+    /// Number of bytes which are queued but not written yet
+    pending: usize,
+    error: Option<io::Error>,
+}
+
+impl WriterThread {
+    fn new(fd: OwnedFd, timeout: Option<time::Duration>) -> io::Result<Self> {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let (wake, waker) = cloexec_pipe()?;
+        let mut file = File::from(fd);
+        let size = chunk_size(&file)?;
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState {
+                pending: 0,
+                error: None,
+            }),
+            changed: Condvar::new(),
+        });
+        let handle = thread::spawn({
+            let queue = queue.clone();
+            move || {
+                for input in rx {
+                    let result = write_until_woken(&mut file, size, &wake, &input);
+                    let Ok(mut state) = queue.state.lock() else {
+                        return;
+                    };
+                    state.pending -= input.len();
+                    let failed = result.is_err();
+                    state.error = result.err();
+                    drop(state);
+                    queue.changed.notify_all();
+                    if failed {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            tx: Some(tx),
+            queue,
+            handle: Some(handle),
+            waker: Some(waker),
+            timeout,
+        })
+    }
+
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, QueueState>> {
+        self.queue
+            .state
+            .lock()
+            .map_err(|_| io::Error::other("writer thread panicked"))
+    }
+}
+
+impl Write for WriterThread {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.lock()?;
+        if let Some(error) = state.error.take() {
+            return Err(error);
+        }
+        let sent = self
+            .tx
+            .as_ref()
+            .is_some_and(|tx| tx.send(buf.to_vec()).is_ok());
+        if !sent {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.pending += buf.len();
+        Ok(buf.len())
+    }
+
+    /// Wait until the queued input was written, at most the write timeout
+    fn flush(&mut self) -> io::Result<()> {
+        let state = self.lock()?;
+        let busy = |state: &mut QueueState| state.pending > 0 && state.error.is_none();
+        let mut state = match self.timeout {
+            Some(timeout) => {
+                let (state, result) = self
+                    .queue
+                    .changed
+                    .wait_timeout_while(state, timeout, busy)
+                    .map_err(|_| io::Error::other("writer thread panicked"))?;
+                if result.timed_out() {
+                    return Err(timed_out());
+                }
+                state
+            }
+            None => self
+                .queue
+                .changed
+                .wait_while(state, busy)
+                .map_err(|_| io::Error::other("writer thread panicked"))?,
+        };
+        match state.error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for WriterThread {
+    fn drop(&mut self) {
+        // the thread still writes what is queued as long as the process accepts it
+        // right away, but doesn't wait for a process which doesn't read its input
+        self.tx.take();
+        self.waker.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Write all of `input`, unless `wake` is closed while the process doesn't accept it
+fn write_until_woken(file: &mut File, size: usize, wake: &OwnedFd, input: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    while written < input.len() {
+        let mut fds = [
+            PollFd::new(file.as_fd(), PollFlags::POLLOUT),
+            PollFd::new(wake.as_fd(), PollFlags::POLLIN),
+        ];
+        match poll(&mut fds, PollTimeout::NONE) {
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+        match fds[0].revents() {
+            Some(revents) if hung_up(revents) => return Err(io::ErrorKind::BrokenPipe.into()),
+            Some(revents) if !revents.is_empty() => {}
+            _ => return Err(io::Error::other("the writer was dropped")),
+        }
+        match write_writable(file, size, &input[written..])? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => written += n,
+        }
+    }
+    Ok(())
+}